@@ -4,4 +4,5 @@ if [[ ! -f ./songsdb/songs.db ]]; then
   ./importer --db ./songsdb/songs.db -s 5 ./songs/
 fi
 
-./karaoke-server -c config.docker.yaml
+# exec, so the server gets the SIGTERM from docker and can save the playlist.
+exec ./karaoke-server -c config.docker.yaml
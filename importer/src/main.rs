@@ -104,6 +104,7 @@ fn parse_txt(
         player_count,
         cover_path,
         audio_path,
+        f64::from(song.header.bpm),
     ))?;
 
     if changes == 1 {
@@ -171,10 +172,23 @@ fn main() -> anyhow::Result<()> {
         lyrics TEXT,
         player_count INTEGER,
         cover_path BLOB,
-        audio_path BLOB
+        audio_path BLOB,
+        explicit INTEGER NOT NULL DEFAULT 0,
+        bpm REAL
     )"#,
         (),
     )?;
+    // Databases created before the explicit flag existed need the column added.
+    // The importer never writes it, so flags set manually survive re-imports.
+    if conn.prepare("SELECT explicit FROM song LIMIT 0").is_err() {
+        conn.execute(
+            "ALTER TABLE song ADD COLUMN explicit INTEGER NOT NULL DEFAULT 0",
+            (),
+        )?;
+    }
+    if conn.prepare("SELECT bpm FROM song LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE song ADD COLUMN bpm REAL", ())?;
+    }
 
     let tx = conn.transaction()?;
     {
@@ -188,8 +202,8 @@ fn main() -> anyhow::Result<()> {
         let mut new_songs = HashSet::new();
 
         let mut insert_stmt = tx.prepare(
-            r#"INSERT INTO song (path, title, artist, language, year, duration, lyrics, player_count, cover_path, audio_path) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-            ON CONFLICT (path) DO UPDATE SET title=?2, artist=?3, language=?4, year=?5, duration=?6, lyrics=?7, player_count = ?8, cover_path=?9, audio_path=?10"#)?;
+            r#"INSERT INTO song (path, title, artist, language, year, duration, lyrics, player_count, cover_path, audio_path, bpm) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            ON CONFLICT (path) DO UPDATE SET title=?2, artist=?3, language=?4, year=?5, duration=?6, lyrics=?7, player_count = ?8, cover_path=?9, audio_path=?10, bpm=?11"#)?;
         walk_dir(
             args.path,
             args.strip_components,
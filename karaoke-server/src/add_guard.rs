@@ -0,0 +1,44 @@
+use std::fmt::Debug;
+
+use futures_util::{future::BoxFuture, FutureExt};
+use sha256::digest;
+
+/// Decides whether a song request is allowed into the queue, e.g. by checking a captcha token.
+pub trait AddGuard: Debug + Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        song: i64,
+        singer: &'a str,
+        token: Option<&'a str>,
+    ) -> BoxFuture<'a, bool>;
+}
+
+/// Requires the client to find a token so that the sha256 of `"{song}:{singer}:{token}"`
+/// starts with the configured number of zeroes (in hex).
+#[derive(Debug)]
+pub struct ProofOfWork {
+    difficulty: usize,
+}
+
+impl ProofOfWork {
+    pub fn new(difficulty: usize) -> Self {
+        Self { difficulty }
+    }
+}
+
+impl AddGuard for ProofOfWork {
+    fn verify<'a>(
+        &'a self,
+        song: i64,
+        singer: &'a str,
+        token: Option<&'a str>,
+    ) -> BoxFuture<'a, bool> {
+        let verified = token.is_some_and(|token| {
+            digest(format!("{song}:{singer}:{token}"))
+                .chars()
+                .take(self.difficulty)
+                .all(|c| c == '0')
+        });
+        async move { verified }.boxed()
+    }
+}
@@ -0,0 +1,104 @@
+use futures_util::{future::BoxFuture, FutureExt};
+
+use crate::songs::{SearchIndex, Song};
+
+/// Where the playlist looks up the songs it queues.
+pub trait SongCatalog: Send + Sync {
+    /// The songs with the given ids, in no particular order. Unknown ids are skipped.
+    fn songs_by_ids(&self, ids: Vec<i64>) -> BoxFuture<'_, anyhow::Result<Vec<Song>>>;
+    /// Whether songs can currently be looked up.
+    fn is_available(&self) -> BoxFuture<'_, bool>;
+    /// How many songs the catalog holds.
+    fn song_count(&self) -> usize;
+}
+
+impl SongCatalog for SearchIndex {
+    fn songs_by_ids(&self, ids: Vec<i64>) -> BoxFuture<'_, anyhow::Result<Vec<Song>>> {
+        async move {
+            if ids.is_empty() {
+                return Ok(Vec::new());
+            }
+            let query = ids
+                .iter()
+                .map(|song| format!("rowid:{song}"))
+                .collect::<Vec<_>>()
+                .join(" OR ");
+            self.search_song_blocking(query, ids.len()).await
+        }
+        .boxed()
+    }
+
+    fn is_available(&self) -> BoxFuture<'_, bool> {
+        async move {
+            match self.search_ids("rowid:0", 1) {
+                Ok(_) => true,
+                Err(err) => {
+                    log::error!("Search index query failed: {err:?}");
+                    false
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn song_count(&self) -> usize {
+        self.doc_count()
+    }
+}
+
+/// A fixed set of songs kept in memory, for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryCatalog {
+    songs: std::collections::HashMap<i64, Song>,
+}
+
+#[cfg(test)]
+impl MemoryCatalog {
+    pub fn new(songs: impl IntoIterator<Item = Song>) -> Self {
+        Self {
+            songs: songs.into_iter().map(|song| (song.row_id, song)).collect(),
+        }
+    }
+
+    /// A song with the given id, artist and title that is three minutes long.
+    pub fn song(row_id: i64, artist: &str, title: &str) -> Song {
+        Song {
+            row_id,
+            title: title.to_owned(),
+            artist: artist.to_owned(),
+            language: None,
+            year: None,
+            duration: 180.0,
+            lyrics: None,
+            duet: false,
+            explicit: false,
+            cover_path: None,
+            audio_path: format!("{row_id}.mp3"),
+            tempo: None,
+        }
+    }
+
+    pub fn ids(&self) -> Vec<i64> {
+        self.songs.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+impl SongCatalog for MemoryCatalog {
+    fn songs_by_ids(&self, ids: Vec<i64>) -> BoxFuture<'_, anyhow::Result<Vec<Song>>> {
+        let songs = ids
+            .iter()
+            .filter_map(|id| self.songs.get(id).cloned())
+            .collect();
+        async move { Ok(songs) }.boxed()
+    }
+
+    fn is_available(&self) -> BoxFuture<'_, bool> {
+        async move { true }.boxed()
+    }
+
+    fn song_count(&self) -> usize {
+        self.songs.len()
+    }
+}
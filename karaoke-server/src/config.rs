@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     net::{SocketAddr, ToSocketAddrs},
     path::{Path, PathBuf},
 };
@@ -6,6 +7,8 @@ use std::{
 use serde::{Deserialize, Deserializer};
 use tokio::fs::read;
 
+use crate::log_sink::CsvLogs;
+
 #[derive(Deserialize, Debug)]
 pub struct Paths {
     /// The path to the sqlite database with the song information.
@@ -22,6 +25,10 @@ pub struct Paths {
     pub suggestion_log: PathBuf,
     /// Path to the file that should contain the song bug reports that were made.
     pub bug_log: PathBuf,
+    /// Directory for session archives. If set, one is written when the server shuts down.
+    pub archives: Option<PathBuf>,
+    /// CSV file with measured song durations (audio path, seconds) that override the database.
+    pub durations: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -33,10 +40,247 @@ pub struct Server {
     pub password: String,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SongLogColumn {
+    /// When the song started playing (RFC 3339).
+    Timestamp,
+    Artist,
+    Title,
+    /// Who sang the song.
+    Singer,
+    /// Who queued the song, which can be someone else than the singer.
+    RequestedBy,
+    /// The id of the song in the database.
+    SongId,
+    /// The id of the playlist entry.
+    EntryId,
+    /// The length of the song in seconds.
+    Duration,
+    /// The session (e.g. the event or venue) the song was played in, empty outside of sessions.
+    Session,
+    /// A score given after the performance, empty if there was none. With this column, rows are
+    /// written once the performance was rated or the next song started.
+    Rating,
+}
+
+/// What to do when a song is added that looks like one already waiting in the queue.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SimilarSongPolicy {
+    #[default]
+    Off,
+    /// Add it anyway, but tell the client.
+    Warn,
+    /// Refuse to add it.
+    Block,
+}
+
+/// What to do when the signature of the playlist file doesn't match.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TamperedPlaylistPolicy {
+    /// Refuse to start.
+    #[default]
+    Refuse,
+    /// Move the file aside and start with an empty playlist.
+    Discard,
+}
+
+/// What to do when the playlist is loaded without any songs in the catalog.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyCatalogPolicy {
+    /// Start anyway and log a warning, songs can be added after reloading the catalog.
+    #[default]
+    Warn,
+    /// Refuse to start.
+    Fail,
+}
+
+/// The unit song durations are stored in by the catalog.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DurationUnit {
+    #[default]
+    Seconds,
+    Milliseconds,
+    Minutes,
+}
+
+/// Which name of an entry counts as its singer.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SingerField {
+    /// Who queued the song.
+    #[default]
+    RequestedBy,
+    /// Who sings it, falling back to who queued it.
+    PerformedBy,
+}
+
+/// What to do when a played song can't be written to the song log.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFailurePolicy {
+    /// Play the song anyway.
+    #[default]
+    BestEffort,
+    /// Don't play the song, so the log stays complete.
+    Strict,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Queue {
+    /// How many played songs are kept in the live history (including the one currently playing).
+    /// The song log is unaffected by this.
+    pub max_play_history: usize,
+    /// How many of the kept played songs are sent to listeners, all of them if unset.
+    /// Can't be more than `max_play_history`, older plays aren't kept.
+    pub shown_play_history: Option<usize>,
+    /// Reorder the queue on every add so that the same artist doesn't come up twice in a row (where possible).
+    pub spread_artists: bool,
+    /// Hide songs marked as explicit from searches and refuse to queue them.
+    pub family_friendly: bool,
+    /// How many seconds before an entry is predicted to start the singer gets a heads-up. 0 disables it.
+    pub up_soon_warning: u64,
+    /// How many seconds before the current song ends the screen is told which song comes next. 0 disables it.
+    pub preload_lookahead: u64,
+    /// Number of leading zeroes (in hex) a proof of work token for adding songs needs. 0 disables it.
+    pub proof_of_work: usize,
+    /// Keep played songs in the list marked as played instead of moving them to the history.
+    pub keep_played_in_list: bool,
+    /// How many other songs have to be between two songs of the same singer. New songs are queued at
+    /// least that far after the singer's previous one (at the end if the queue is too short), and
+    /// automatic reordering keeps the gap.
+    pub min_songs_between_same_singer: usize,
+    /// The columns written to the song log, in order.
+    pub song_log_columns: Vec<SongLogColumn>,
+    /// Only send this many queued entries to listeners (unlimited if not set).
+    pub max_broadcast_entries: Option<usize>,
+    /// Song ids that are the same song as another one in the catalog, mapped to the id that should be used instead.
+    pub song_aliases: HashMap<i64, i64>,
+    /// For how many seconds the entries of a removed singer can be restored.
+    pub singer_restore_grace: u64,
+    /// Append each change to a log next to the playlist file instead of rewriting the whole file every time.
+    /// Statistics that changed without a change to the playlist are logged with the next one.
+    pub write_ahead_log: bool,
+    /// Whether songs with (almost) the same artist and title as a waiting one can be added.
+    /// The exact same song is also caught when it's playing or anywhere in the queue.
+    pub similar_songs: SimilarSongPolicy,
+    /// How many of the last waiting entries are compared against. The exact same song is found
+    /// anywhere in the queue.
+    pub similar_songs_window: usize,
+    /// Share of words in artist and title (0 to 1) two songs need to have in common to count as similar.
+    pub similar_songs_threshold: f64,
+    /// Tell clients when someone else with the same name (but a different password) is waiting already.
+    pub warn_same_name: bool,
+    /// After this many songs the entry that was added the longest time ago moves to the front. 0 disables it.
+    pub auto_promote_every: usize,
+    /// How many wrong passwords can be tried for an entry before it is locked. 0 allows unlimited attempts.
+    pub password_attempts: usize,
+    /// For how many seconds after the first wrong password attempts are counted (and an entry stays locked).
+    pub password_lockout: u64,
+    /// The singer name used for encores.
+    pub encore_singer: String,
+    /// Queue encores even if a similar song is waiting and those are blocked.
+    pub encore_ignores_similar: bool,
+    /// Also send the server time and the seconds until each entry ends, for clients with a wrong clock.
+    pub relative_times: bool,
+    /// Only predict when the first this many queued entries end, the rest are marked as `later`.
+    pub prediction_horizon: Option<usize>,
+    /// Whether a song can be played when writing it to the song log failed.
+    pub song_log_failures: LogFailurePolicy,
+    /// A secret mixed into the passwords of entries before hashing them, so the hashes in the playlist
+    /// file are useless without it. Changing it invalidates the passwords of all waiting entries.
+    pub password_pepper: Option<String>,
+    /// How many seconds a reserved slot waits to be claimed.
+    pub reservation_ttl: u64,
+    /// For how many seconds the singer name last added from a device is remembered.
+    pub device_memory: u64,
+    /// Whether the singer spacing and the fairness report go by who requested or who performs a song.
+    pub fairness_counts: SingerField,
+    /// When only predictions changed, send just those instead of the whole playlist.
+    pub compact_predictions: bool,
+    /// Songs that would have to wait longer than this many seconds can't be added.
+    pub max_predicted_wait: Option<u64>,
+    /// The unit of the song durations in the database.
+    pub duration_unit: DurationUnit,
+    /// Predict songs played before with how long they actually took instead of their duration.
+    pub learn_durations: bool,
+    /// Seconds an entry keeps its position while its payment is confirmed.
+    pub hold_timeout: u64,
+    /// Seconds that have to pass between two performances of the same singer. New songs are queued
+    /// late enough for that, going by the predictions (0 disables it).
+    pub singer_cooldown: u64,
+    /// What to do when the catalog is empty at startup.
+    pub empty_catalog: EmptyCatalogPolicy,
+    /// Key for signing the playlist file and the write-ahead log, so changes by hand are noticed.
+    pub persist_key: Option<String>,
+    /// What to do when the playlist file was changed without the key.
+    pub tampered_playlist: TamperedPlaylistPolicy,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            max_play_history: 3,
+            shown_play_history: None,
+            spread_artists: false,
+            family_friendly: false,
+            up_soon_warning: 0,
+            preload_lookahead: 0,
+            proof_of_work: 0,
+            keep_played_in_list: false,
+            min_songs_between_same_singer: 0,
+            song_log_columns: vec![
+                SongLogColumn::Timestamp,
+                SongLogColumn::Artist,
+                SongLogColumn::Title,
+            ],
+            max_broadcast_entries: None,
+            song_aliases: HashMap::new(),
+            singer_restore_grace: 600,
+            write_ahead_log: false,
+            similar_songs: SimilarSongPolicy::Off,
+            similar_songs_window: 10,
+            similar_songs_threshold: 0.9,
+            warn_same_name: false,
+            auto_promote_every: 0,
+            password_attempts: 5,
+            password_lockout: 300,
+            encore_singer: "Encore".to_owned(),
+            encore_ignores_similar: true,
+            relative_times: false,
+            prediction_horizon: None,
+            song_log_failures: LogFailurePolicy::BestEffort,
+            password_pepper: None,
+            reservation_ttl: 900,
+            device_memory: 1800,
+            fairness_counts: SingerField::RequestedBy,
+            compact_predictions: false,
+            max_predicted_wait: None,
+            duration_unit: DurationUnit::Seconds,
+            learn_durations: false,
+            hold_timeout: 300,
+            singer_cooldown: 0,
+            empty_catalog: EmptyCatalogPolicy::Warn,
+            persist_key: None,
+            tampered_playlist: TamperedPlaylistPolicy::Refuse,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub paths: Paths,
     pub server: Server,
+    #[serde(default)]
+    pub queue: Queue,
+    /// Where the song, bug and suggestion logs are written to.
+    #[serde(default)]
+    pub csv_logs: CsvLogs,
     pub logging: log4rs::config::RawConfig,
 }
 
@@ -0,0 +1,34 @@
+use std::{collections::HashMap, fmt::Debug, path::Path};
+
+use crate::songs::Song;
+
+/// Knows song durations better than the catalog, e.g. because the videos that are actually played
+/// are longer than the audio the catalog was built from.
+pub trait DurationProvider: Debug + Send + Sync {
+    /// The duration of the song in seconds, `None` if the catalog's duration should be used.
+    fn duration(&self, song: &Song) -> Option<f64>;
+}
+
+/// Durations measured by the operator, read from a CSV file with the audio path of a song
+/// (as in the database) and its duration in seconds on each line.
+#[derive(Debug)]
+pub struct MeasuredDurations {
+    durations: HashMap<String, f64>,
+}
+
+impl MeasuredDurations {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let durations = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?;
+        Ok(Self { durations })
+    }
+}
+
+impl DurationProvider for MeasuredDurations {
+    fn duration(&self, song: &Song) -> Option<f64> {
+        self.durations.get(&song.audio_path).copied()
+    }
+}
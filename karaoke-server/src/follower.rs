@@ -0,0 +1,115 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{extract::State, routing::get, Json, Router};
+use futures_util::StreamExt;
+use tokio::sync::RwLock;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tower::ServiceBuilder;
+use tower_http::services::ServeDir;
+
+use crate::{
+    add_cors_headers,
+    now_playing::{PlaylistEntry, PlaylistFollower, PlaylistState},
+    shutdown_signal,
+};
+
+/// How long to wait before connecting to the primary again after losing the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type SharedFollower = Arc<RwLock<PlaylistFollower>>;
+
+/// Keeps `follower` in sync with the websocket at `url`, reconnecting whenever the connection is lost.
+async fn mirror(url: String, follower: SharedFollower) {
+    loop {
+        // Only ask for the whole playlist if ours is outdated.
+        let connect_url = match follower.read().await.version() {
+            Some(version) => format!("{url}?version={version}"),
+            None => url.clone(),
+        };
+        match connect_async(&connect_url).await {
+            Ok((mut socket, _)) => {
+                log::info!("Following {url}");
+                while let Some(message) = socket.next().await {
+                    match message {
+                        Ok(Message::Text(text)) => {
+                            let mut follower = follower.write().await;
+                            let before = follower.state();
+                            match follower.apply_broadcast(&text) {
+                                Ok(false) => {}
+                                Ok(true) if follower.is_empty() => log::info!(
+                                    "Mirrored version {:?}, the list is empty",
+                                    follower.version()
+                                ),
+                                Ok(true) => {
+                                    let diff = before.diff(&follower.state());
+                                    log::info!(
+                                        "Mirrored version {:?}, {} entries in the list ({} added, {} removed, {} moved)",
+                                        follower.version(),
+                                        follower.len(),
+                                        diff.added.len(),
+                                        diff.removed.len(),
+                                        diff.moved.len()
+                                    )
+                                }
+                                Err(err) => {
+                                    log::error!("Failed parsing message from {url}: {err:?}")
+                                }
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            log::error!("Connection to {url} failed: {err:?}");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => log::error!("Connecting to {url} failed: {err:?}"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn get_state(State(follower): State<SharedFollower>) -> Json<PlaylistState> {
+    Json(follower.read().await.state())
+}
+
+async fn get_now_playing(State(follower): State<SharedFollower>) -> Json<Option<PlaylistEntry>> {
+    Json(
+        follower
+            .read()
+            .await
+            .now_playing()
+            .map(PlaylistEntry::scrubbed),
+    )
+}
+
+async fn get_up_next(State(follower): State<SharedFollower>) -> Json<Option<PlaylistEntry>> {
+    Json(follower.read().await.up_next().map(PlaylistEntry::scrubbed))
+}
+
+/// Serves a read-only copy of the playlist of the server at `url`, e.g. for a second screen.
+pub async fn follow(
+    url: String,
+    address: SocketAddr,
+    web_app: PathBuf,
+    media: PathBuf,
+) -> anyhow::Result<()> {
+    let follower = SharedFollower::default();
+    tokio::spawn(mirror(url, follower.clone()));
+
+    let app = Router::new()
+        .route("/api/state", get(get_state))
+        .route("/api/now_playing", get(get_now_playing))
+        .route("/api/up_next", get(get_up_next))
+        .nest_service("/media", ServeDir::new(media))
+        .nest_service("/", ServeDir::new(web_app))
+        .with_state(follower)
+        .layer(ServiceBuilder::new().layer(axum::middleware::from_fn(add_cors_headers)));
+    log::info!("Listening on {address:?}");
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    Ok(())
+}
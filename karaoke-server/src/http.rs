@@ -0,0 +1,557 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use uuid::Uuid;
+
+use crate::{
+    catalog::SongCatalog,
+    now_playing::{AddOptions, AddResult, BatchResult, EditResult, Playlist, QueueOp},
+};
+
+/// Plain HTTP versions of the websocket commands for the queue, for integrations that can't keep a
+/// websocket open. Except for adding songs and subscribing, requests need the admin password as a
+/// bearer token.
+#[derive(Clone)]
+pub struct QueueApi {
+    pub playlist: Arc<Playlist>,
+    pub catalog: Arc<dyn SongCatalog>,
+    /// With an empty password, all admin requests are refused.
+    pub password: Arc<str>,
+}
+
+/// Why a request to the queue failed.
+#[derive(Debug)]
+pub enum PlaylistError {
+    /// The request needs the admin password.
+    Unauthorized,
+    /// There's no song with this id in the catalog.
+    UnknownSong,
+    /// There's no entry with this id in the queue, or the edit doesn't apply to it.
+    UnknownEntry,
+    /// The song is explicit, or the add guard rejected the request.
+    Forbidden(AddResult),
+    /// The queue doesn't take the song right now: it's closed, too long or a similar song is waiting.
+    Refused(AddResult),
+    /// The entry has to be unpinned first.
+    Pinned,
+    /// The entry's position is held until its payment is confirmed.
+    Held,
+    /// Too many wrong passwords were tried for the entry.
+    Locked,
+    /// One of the operations didn't change anything, so none of them were applied.
+    BatchFailed(BatchResult),
+    Internal(anyhow::Error),
+}
+
+impl PlaylistError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PlaylistError::Unauthorized => StatusCode::UNAUTHORIZED,
+            PlaylistError::UnknownSong | PlaylistError::UnknownEntry => StatusCode::NOT_FOUND,
+            PlaylistError::Forbidden(_) => StatusCode::FORBIDDEN,
+            PlaylistError::Refused(_)
+            | PlaylistError::Pinned
+            | PlaylistError::Held
+            | PlaylistError::BatchFailed(_) => StatusCode::CONFLICT,
+            PlaylistError::Locked => StatusCode::LOCKED,
+            PlaylistError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            PlaylistError::Unauthorized => "unauthorized",
+            PlaylistError::UnknownSong => "unknownSong",
+            PlaylistError::UnknownEntry => "unknownEntry",
+            PlaylistError::Forbidden(_) => "forbidden",
+            PlaylistError::Refused(_) => "refused",
+            PlaylistError::Pinned => "pinned",
+            PlaylistError::Held => "held",
+            PlaylistError::Locked => "locked",
+            PlaylistError::BatchFailed(_) => "batchFailed",
+            PlaylistError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl From<anyhow::Error> for PlaylistError {
+    fn from(err: anyhow::Error) -> Self {
+        PlaylistError::Internal(err)
+    }
+}
+
+fn edited(result: EditResult) -> Result<(), PlaylistError> {
+    match result {
+        EditResult::Changed => Ok(()),
+        EditResult::Unchanged => Err(PlaylistError::UnknownEntry),
+        EditResult::Pinned => Err(PlaylistError::Pinned),
+        EditResult::Held => Err(PlaylistError::Held),
+        EditResult::Locked => Err(PlaylistError::Locked),
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<AddResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch: Option<&'a BatchResult>,
+}
+
+impl IntoResponse for PlaylistError {
+    fn into_response(self) -> Response {
+        if let PlaylistError::Internal(err) = &self {
+            log::error!("Queue request failed: {err:?}");
+        }
+        let body = ErrorBody {
+            error: self.kind(),
+            result: match &self {
+                PlaylistError::Forbidden(result) | PlaylistError::Refused(result) => Some(*result),
+                _ => None,
+            },
+            batch: match &self {
+                PlaylistError::BatchFailed(batch) => Some(batch),
+                _ => None,
+            },
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddRequest {
+    pub song: i64,
+    pub singer: String,
+    /// Needed to remove the entry again. Without one, only admins can remove it.
+    pub password: Option<String>,
+    /// Who sings the song, if that's not the one adding it.
+    pub performer: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Added {
+    pub id: Uuid,
+    /// A waiting entry that is (almost) the same song.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similar: Option<Uuid>,
+    /// A waiting entry with the same singer name and a different password.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_name: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntryRequest {
+    pub id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveAfterRequest {
+    pub id: Uuid,
+    pub after: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SwapRequest {
+    pub id1: Uuid,
+    pub id2: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeRequest {
+    /// The playlist version the client already has, it's only sent again if it changed.
+    pub version: Option<u64>,
+}
+
+/// Sent for every successful edit of an entry.
+#[derive(Debug, Serialize)]
+pub struct Edited {
+    pub id: Uuid,
+}
+
+/// Hashing first keeps the comparison from taking longer the more of the password is right.
+fn same_secret(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter()
+        .zip(b.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+impl QueueApi {
+    fn authorize(&self, token: Option<&str>) -> Result<(), PlaylistError> {
+        match token {
+            Some(token) if !self.password.is_empty() && same_secret(token, &self.password) => {
+                Ok(())
+            }
+            _ => Err(PlaylistError::Unauthorized),
+        }
+    }
+
+    pub async fn add(
+        &self,
+        who: impl std::fmt::Debug,
+        request: AddRequest,
+    ) -> Result<Added, PlaylistError> {
+        let options = AddOptions {
+            password: request.password,
+            performed_by: request.performer,
+            ..Default::default()
+        };
+        let result = self
+            .playlist
+            .add(request.song, request.singer, options, &*self.catalog)
+            .await?;
+        result.log(who, request.song);
+        match result {
+            AddResult::Added(id) => Ok(Added {
+                id,
+                similar: None,
+                same_name: None,
+            }),
+            AddResult::AddedSimilar { id, similar } => Ok(Added {
+                id,
+                similar: Some(similar),
+                same_name: None,
+            }),
+            AddResult::AddedSameName { id, other } => Ok(Added {
+                id,
+                similar: None,
+                same_name: Some(other),
+            }),
+            AddResult::UnknownSong | AddResult::UnknownClaimCode => Err(PlaylistError::UnknownSong),
+            AddResult::Explicit | AddResult::Unverified => Err(PlaylistError::Forbidden(result)),
+            AddResult::AddsClosed | AddResult::QueueTooLong | AddResult::Similar(_) => {
+                Err(PlaylistError::Refused(result))
+            }
+        }
+    }
+
+    pub async fn remove(
+        &self,
+        token: Option<&str>,
+        request: EntryRequest,
+    ) -> Result<Edited, PlaylistError> {
+        self.authorize(token)?;
+        edited(self.playlist.remove(request.id, &*self.catalog).await?)?;
+        Ok(Edited { id: request.id })
+    }
+
+    pub async fn play(
+        &self,
+        token: Option<&str>,
+        request: EntryRequest,
+    ) -> Result<Edited, PlaylistError> {
+        self.authorize(token)?;
+        if self.playlist.play(request.id, &*self.catalog).await? {
+            Ok(Edited { id: request.id })
+        } else {
+            Err(PlaylistError::UnknownEntry)
+        }
+    }
+
+    pub async fn move_top(
+        &self,
+        token: Option<&str>,
+        request: EntryRequest,
+    ) -> Result<Edited, PlaylistError> {
+        self.authorize(token)?;
+        edited(self.playlist.move_top(request.id, &*self.catalog).await?)?;
+        Ok(Edited { id: request.id })
+    }
+
+    pub async fn move_after(
+        &self,
+        token: Option<&str>,
+        request: MoveAfterRequest,
+    ) -> Result<Edited, PlaylistError> {
+        self.authorize(token)?;
+        let result = self
+            .playlist
+            .move_after(request.id, request.after, &*self.catalog)
+            .await?;
+        edited(result)?;
+        Ok(Edited { id: request.id })
+    }
+
+    pub async fn swap(
+        &self,
+        token: Option<&str>,
+        request: SwapRequest,
+    ) -> Result<Edited, PlaylistError> {
+        self.authorize(token)?;
+        let result = self
+            .playlist
+            .swap(request.id1, request.id2, &*self.catalog)
+            .await?;
+        edited(result)?;
+        Ok(Edited { id: request.id1 })
+    }
+
+    pub async fn batch(
+        &self,
+        token: Option<&str>,
+        ops: Vec<QueueOp>,
+    ) -> Result<BatchResult, PlaylistError> {
+        self.authorize(token)?;
+        let result = self.playlist.batch(ops, &*self.catalog).await?;
+        if result.applied {
+            Ok(result)
+        } else {
+            Err(PlaylistError::BatchFailed(result))
+        }
+    }
+
+    /// The messages a websocket listener gets, starting with the playlist (or that it's up to date).
+    /// Dropping the receiver ends the subscription.
+    pub async fn subscribe(
+        &self,
+        request: SubscribeRequest,
+    ) -> Result<UnboundedReceiver<String>, PlaylistError> {
+        let (sender, receiver) = unbounded_channel();
+        self.playlist.subscribe(sender, request.version).await?;
+        Ok(receiver)
+    }
+
+    pub fn routes<S>(self) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        Router::new()
+            .route("/api/queue/add", post(add))
+            .route("/api/queue/remove", post(remove))
+            .route("/api/queue/play", post(play))
+            .route("/api/queue/move_top", post(move_top))
+            .route("/api/queue/move_after", post(move_after))
+            .route("/api/queue/swap", post(swap))
+            .route("/api/queue/batch", post(batch))
+            .route("/api/queue/subscribe", get(subscribe))
+            .with_state(self)
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+pub async fn add(
+    State(api): State<QueueApi>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Json(request): Json<AddRequest>,
+) -> Result<(StatusCode, Json<Added>), PlaylistError> {
+    let who = connect_info.map(|ConnectInfo(address)| address);
+    Ok((StatusCode::CREATED, Json(api.add(who, request).await?)))
+}
+
+pub async fn remove(
+    State(api): State<QueueApi>,
+    headers: HeaderMap,
+    Json(request): Json<EntryRequest>,
+) -> Result<Json<Edited>, PlaylistError> {
+    Ok(Json(api.remove(bearer_token(&headers), request).await?))
+}
+
+pub async fn play(
+    State(api): State<QueueApi>,
+    headers: HeaderMap,
+    Json(request): Json<EntryRequest>,
+) -> Result<Json<Edited>, PlaylistError> {
+    Ok(Json(api.play(bearer_token(&headers), request).await?))
+}
+
+pub async fn move_top(
+    State(api): State<QueueApi>,
+    headers: HeaderMap,
+    Json(request): Json<EntryRequest>,
+) -> Result<Json<Edited>, PlaylistError> {
+    Ok(Json(api.move_top(bearer_token(&headers), request).await?))
+}
+
+pub async fn move_after(
+    State(api): State<QueueApi>,
+    headers: HeaderMap,
+    Json(request): Json<MoveAfterRequest>,
+) -> Result<Json<Edited>, PlaylistError> {
+    Ok(Json(api.move_after(bearer_token(&headers), request).await?))
+}
+
+pub async fn swap(
+    State(api): State<QueueApi>,
+    headers: HeaderMap,
+    Json(request): Json<SwapRequest>,
+) -> Result<Json<Edited>, PlaylistError> {
+    Ok(Json(api.swap(bearer_token(&headers), request).await?))
+}
+
+pub async fn batch(
+    State(api): State<QueueApi>,
+    headers: HeaderMap,
+    Json(ops): Json<Vec<QueueOp>>,
+) -> Result<Json<BatchResult>, PlaylistError> {
+    Ok(Json(api.batch(bearer_token(&headers), ops).await?))
+}
+
+/// Streams the playlist updates as server-sent events.
+pub async fn subscribe(
+    State(api): State<QueueApi>,
+    Query(request): Query<SubscribeRequest>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, PlaylistError> {
+    let receiver = api.subscribe(request).await?;
+    let events = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let message = receiver.recv().await?;
+        Some((Ok(sse::Event::default().data(message)), receiver))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+    use crate::{
+        catalog::MemoryCatalog, config::Queue, log_sink::ChannelSink, now_playing::TempPlaylistFile,
+    };
+
+    /// The API with its playlist in a temporary file, which is removed when the file is dropped.
+    async fn api(password: &str) -> (QueueApi, TempPlaylistFile) {
+        let catalog = MemoryCatalog::new([
+            MemoryCatalog::song(1, "Artist", "First"),
+            MemoryCatalog::song(2, "Artist", "Second"),
+        ]);
+        let file = TempPlaylistFile::unique();
+        let (sender, _) = unbounded_channel();
+        let playlist = Playlist::load(
+            &file.path,
+            catalog.ids(),
+            None,
+            Box::new(ChannelSink::new(sender)),
+            None,
+            None,
+            Queue::default(),
+        )
+        .await
+        .unwrap();
+        let api = QueueApi {
+            playlist: Arc::new(playlist),
+            catalog: Arc::new(catalog),
+            password: password.into(),
+        };
+        (api, file)
+    }
+
+    fn authorized(password: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {password}").parse().unwrap(),
+        );
+        headers
+    }
+
+    async fn respond(response: impl IntoResponse) -> (StatusCode, serde_json::Value) {
+        let response = response.into_response();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn add_request(song: i64) -> Json<AddRequest> {
+        Json(AddRequest {
+            song,
+            singer: "Alice".to_owned(),
+            password: None,
+            performer: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn add_reports_created_or_unknown_song() {
+        let (api, _file) = api("secret").await;
+
+        let (status, json) = respond(add(State(api.clone()), None, add_request(1)).await).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(json["id"].is_string());
+
+        let (status, json) = respond(add(State(api), None, add_request(42)).await).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json["error"], "unknownSong");
+    }
+
+    #[tokio::test]
+    async fn edits_need_the_password() {
+        let (api, _file) = api("secret").await;
+        let id = api.add("test", add_request(1).0).await.unwrap().id;
+
+        let (status, json) = respond(
+            remove(
+                State(api.clone()),
+                authorized("wrong"),
+                Json(EntryRequest { id }),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(json["error"], "unauthorized");
+
+        let (status, json) = respond(
+            remove(
+                State(api.clone()),
+                authorized("secret"),
+                Json(EntryRequest { id }),
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["id"], id.to_string());
+
+        let (status, json) =
+            respond(remove(State(api), authorized("secret"), Json(EntryRequest { id })).await)
+                .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json["error"], "unknownEntry");
+    }
+
+    #[tokio::test]
+    async fn empty_password_refuses_everything() {
+        let (api, _file) = api("").await;
+        let id = api.add("test", add_request(1).0).await.unwrap().id;
+        let (status, _) =
+            respond(move_top(State(api), authorized(""), Json(EntryRequest { id })).await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_a_conflict() {
+        let (api, _file) = api("secret").await;
+        let id = api.add("test", add_request(1).0).await.unwrap().id;
+        let ops = vec![
+            QueueOp::MoveTop { id },
+            QueueOp::Remove { id: Uuid::new_v4() },
+        ];
+        let (status, json) =
+            respond(batch(State(api), authorized("secret"), Json(ops)).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(json["error"], "batchFailed");
+        assert_eq!(json["batch"]["applied"], false);
+    }
+}
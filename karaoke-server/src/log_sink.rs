@@ -0,0 +1,141 @@
+use std::{borrow::Cow, fmt::Debug, path::Path};
+
+use csv::{StringRecord, Writer};
+use futures_util::{future::BoxFuture, FutureExt};
+use serde::Deserialize;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        Mutex,
+    },
+};
+
+/// Where the CSV logs (played songs, bug reports, suggestions) should go.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvLogs {
+    /// Append to the files configured in `paths`.
+    #[default]
+    File,
+    /// Pass the rows to the application logger, so they can be routed to any log4rs appender.
+    Logger,
+}
+
+/// A destination for CSV rows.
+pub trait LogSink: Debug + Send + Sync {
+    fn write_record(&self, record: StringRecord) -> BoxFuture<'_, anyhow::Result<()>>;
+    /// Whether rows can currently be written.
+    fn is_writable(&self) -> BoxFuture<'_, bool>;
+}
+
+/// Prefixes fields that a spreadsheet would interpret as a formula with a single quote.
+fn neutralize_formula(field: &str) -> Cow<'_, [u8]> {
+    if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{field}").into_bytes())
+    } else {
+        Cow::Borrowed(field.as_bytes())
+    }
+}
+
+fn to_csv(record: &StringRecord) -> anyhow::Result<Vec<u8>> {
+    let mut writer = Writer::from_writer(Vec::new());
+    writer.write_record(record.iter().map(neutralize_formula))?;
+    Ok(writer.into_inner().map_err(|err| err.into_error())?)
+}
+
+/// Appends the rows to a local file.
+#[derive(Debug)]
+pub struct FileSink {
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    pub async fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self {
+            file: Mutex::new(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .await?,
+            ),
+        })
+    }
+}
+
+impl LogSink for FileSink {
+    fn write_record(&self, record: StringRecord) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            let data = to_csv(&record)?;
+            self.file.lock().await.write_all(&data).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn is_writable(&self) -> BoxFuture<'_, bool> {
+        async move {
+            match self.file.lock().await.metadata().await {
+                Ok(metadata) => !metadata.permissions().readonly(),
+                Err(err) => {
+                    log::error!("Log file not accessible: {err:?}");
+                    false
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Hands the rows to a channel, whoever is on the other end decides where they go.
+#[derive(Debug)]
+pub struct ChannelSink {
+    sender: UnboundedSender<StringRecord>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: UnboundedSender<StringRecord>) -> Self {
+        Self { sender }
+    }
+
+    /// Writes every row to the application log with the given target.
+    pub fn logger(target: &'static str) -> Self {
+        let (sender, mut receiver) = unbounded_channel::<StringRecord>();
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                match to_csv(&record) {
+                    Ok(data) => {
+                        log::info!(target: target, "{}", String::from_utf8_lossy(&data).trim_end())
+                    }
+                    Err(err) => log::error!("Failed formatting {target} row: {err:?}"),
+                }
+            }
+        });
+        Self::new(sender)
+    }
+}
+
+impl LogSink for ChannelSink {
+    fn write_record(&self, record: StringRecord) -> BoxFuture<'_, anyhow::Result<()>> {
+        let result = self.sender.send(record).map_err(anyhow::Error::from);
+        async move { result }.boxed()
+    }
+
+    fn is_writable(&self) -> BoxFuture<'_, bool> {
+        let writable = !self.sender.is_closed();
+        async move { writable }.boxed()
+    }
+}
+
+pub async fn open_log_sink(
+    kind: CsvLogs,
+    target: &'static str,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<Box<dyn LogSink>> {
+    Ok(match kind {
+        CsvLogs::File => Box::new(FileSink::open(path).await?),
+        CsvLogs::Logger => Box::new(ChannelSink::logger(target)),
+    })
+}
@@ -1,47 +1,64 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     net::{IpAddr, Ipv6Addr, SocketAddr},
-    path::PathBuf,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 
 use axum::{
     body::Body,
     extract::{Query, State},
-    http::{Request, StatusCode},
+    http::{header, Request, StatusCode},
     middleware::Next,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
 use clap::Parser;
-use csv::{StringRecord, Writer};
-use now_playing::Playlist;
+use csv::StringRecord;
+use now_playing::{
+    AnalyticsExport, ChangesSince, ChurnReport, HealthStatus, PayloadStats, Playlist,
+    PlaylistEntry, PlaylistState, SingerStats, SubscriptionFilter,
+};
 use rusqlite::{Connection, OpenFlags};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
-use tokio::{
-    fs::{File, OpenOptions},
-    io::AsyncWriteExt,
-    sync::Mutex,
-};
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
 use tower_http::{
     services::ServeDir,
     trace::{DefaultMakeSpan, TraceLayer},
 };
+use uuid::Uuid;
 
 use crate::{
+    add_guard::{AddGuard, ProofOfWork},
     config::parse_config,
-    songs::{urlencode_path, SearchIndex, Song},
+    durations::{DurationProvider, MeasuredDurations},
+    log_sink::{open_log_sink, LogSink},
+    songs::{urlencode_path, SearchField, SearchIndex, Song},
     websocket::ws_handler,
 };
 
+mod add_guard;
+mod catalog;
 mod config;
+mod durations;
+mod follower;
+#[cfg(feature = "http")]
+mod http;
+mod log_sink;
 mod now_playing;
 mod songs;
 mod websocket;
 
+/// How long a user search may take before we stop waiting for it.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Parser, Debug)]
 struct Args {
     /// The address and port to listen on (defaults to [::1]:8080).
@@ -50,15 +67,50 @@ struct Args {
     /// The path to the config file in toml format.
     #[clap(short, long)]
     config: PathBuf,
+    /// Mirror the playlist of the server with this websocket url (e.g. ws://primary:8080/ws)
+    /// read-only instead of hosting one.
+    #[clap(long)]
+    follow: Option<String>,
 }
 
 pub struct AppState {
-    song_count: usize,
+    song_count: AtomicUsize,
     index: SearchIndex,
-    playlist: Playlist,
+    playlist: Arc<Playlist>,
     password: String,
-    languages: HashSet<String>,
-    suggest_log: Mutex<File>,
+    languages: RwLock<HashSet<String>>,
+    database: PathBuf,
+    suggest_log: Box<dyn LogSink>,
+    archive_dir: Option<PathBuf>,
+}
+
+/// Resolves when the server should stop: on Ctrl+C, or on SIGTERM as sent by docker and systemd.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            log::error!("Failed listening for Ctrl+C: {err:?}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                log::error!("Failed listening for SIGTERM: {err:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
 }
 
 async fn add_cors_headers(req: Request<Body>, next: Next) -> impl IntoResponse {
@@ -85,87 +137,105 @@ async fn main() -> anyhow::Result<()> {
         ))
     });
 
+    if let Some(url) = args.follow {
+        return follower::follow(url, address, config.paths.web_app, config.paths.media).await;
+    }
+
     log::info!("Loading song database...");
-    let song_db: Vec<Song>;
-    let languages: HashSet<String>;
-    {
-        let mut conn =
-            Connection::open_with_flags(config.paths.database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
-        let tx = conn.transaction()?;
-
-        let mut stmt = tx.prepare(
-            "SELECT rowid, title, artist, language, year, duration, lyrics, player_count, cover_path, audio_path FROM song ORDER BY title COLLATE NOCASE",
-        )?;
-        let mut lang_stmt =
-            tx.prepare("SELECT DISTINCT language FROM song WHERE LANGUAGE IS NOT NULL")?;
-        song_db = stmt
-            .query_map((), |row| {
-                let row_id = row.get("rowid")?;
-                let cover_path = row.get::<_, Option<Vec<u8>>>("cover_path")?;
-                let audio_path = row.get::<_, Option<Vec<u8>>>("audio_path")?;
-                Ok(Song {
-                    row_id,
-                    title: row.get("title")?,
-                    artist: row.get("artist")?,
-                    language: row.get("language")?,
-                    year: row.get("year")?,
-                    duration: row.get("duration")?,
-                    lyrics: row.get("lyrics")?,
-                    duet: row.get::<_, i32>("player_count")? > 1,
-                    cover_path: cover_path.map(urlencode_path),
-                    audio_path: urlencode_path(audio_path.unwrap()),
-                })
-            })?
-            .filter_map(|result| match result {
-                Ok(song) => Some(song),
-                Err(err) => {
-                    log::error!("Failed loading song: {err:?}");
-                    None
-                }
-            })
-            .collect();
-        languages = lang_stmt
-            .query_map((), |row| row.get::<_, String>(0))?
-            .collect::<Result<_, _>>()?;
-    };
+    let (song_db, languages) = load_song_db(&config.paths.database)?;
 
-    let index = SearchIndex::new(song_db.iter())?;
+    let index = SearchIndex::new(song_db.iter(), config.queue.family_friendly)?;
     let song_count = song_db.len();
+    let song_log = if let Some(song_log) = &config.paths.song_log {
+        Some(open_log_sink(config.csv_logs, "song_log", song_log).await?)
+    } else {
+        None
+    };
     let playlist = Playlist::load(
         config.paths.playlist,
         song_db.iter().map(|song| song.row_id),
-        config.paths.song_log.as_deref(),
-        &config.paths.bug_log,
+        song_log,
+        open_log_sink(config.csv_logs, "bug_log", &config.paths.bug_log).await?,
+        (config.queue.proof_of_work > 0)
+            .then(|| Box::new(ProofOfWork::new(config.queue.proof_of_work)) as Box<dyn AddGuard>),
+        if let Some(durations) = &config.paths.durations {
+            Some(Box::new(MeasuredDurations::load(durations)?) as Box<dyn DurationProvider>)
+        } else {
+            None
+        },
+        config.queue,
     )
     .await?;
 
     let state = Arc::new(AppState {
-        song_count,
+        song_count: AtomicUsize::new(song_count),
         index,
-        playlist,
+        playlist: Arc::new(playlist),
         password: config.server.password,
-        languages,
-        suggest_log: Mutex::new(
-            OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(config.paths.suggestion_log)
-                .await?,
-        ),
+        languages: RwLock::new(languages),
+        database: config.paths.database,
+        suggest_log: open_log_sink(
+            config.csv_logs,
+            "suggestion_log",
+            config.paths.suggestion_log,
+        )
+        .await?,
+        archive_dir: config.paths.archives,
     });
 
-    let app = Router::new()
+    let stop_background = CancellationToken::new();
+    let ticker = {
+        let state = state.clone();
+        let stop = stop_background.clone();
+        tokio::spawn(async move {
+            state
+                .playlist
+                .run_ticks(&state.index, Duration::from_secs(5), stop)
+                .await
+        })
+    };
+
+    let router = Router::new()
         .route("/api/song", get(get_song))
         .route("/api/search", post(search))
+        .route("/api/search_ids", post(search_ids))
+        .route("/api/search_duration", post(search_duration))
         .route("/api/all_songs", get(get_all_songs))
         .route("/api/random_songs", get(get_random_songs))
+        .route("/api/unplayed_songs", get(get_unplayed_songs))
         .route("/api/song_count", get(get_song_count))
         .route("/api/languages", get(get_languages))
+        .route("/api/wait_at_position", get(get_wait_at_position))
+        .route("/api/predict_position", get(get_predict_position))
+        .route("/api/changes", get(get_changes))
+        .route("/api/last_singer", get(get_last_singer))
+        .route("/api/state", get(get_state))
+        .route("/api/buggy_songs", get(get_buggy_songs))
+        .route("/api/fairness", get(get_fairness))
+        .route("/api/churn", get(get_churn))
+        .route("/api/pending", get(get_pending))
+        .route("/api/recently_added", get(get_recently_added))
+        .route("/api/singer_schedule", get(get_singer_schedule))
+        .route("/api/payload", get(get_payload))
+        .route("/api/payload_stats", get(get_payload_stats))
+        .route("/api/actual_duration", get(get_actual_duration))
+        .route("/api/analytics", get(get_analytics))
         .route("/api/suggest", post(suggest))
-        .route("/ws", get(ws_handler))
+        .route("/healthz", get(health_check))
+        .route("/ws", get(ws_handler));
+    #[cfg(feature = "http")]
+    let router = router.merge(
+        http::QueueApi {
+            playlist: state.playlist.clone(),
+            catalog: Arc::new(state.index.clone()),
+            password: state.password.as_str().into(),
+        }
+        .routes(),
+    );
+    let app = router
         .nest_service("/media", ServeDir::new(config.paths.media))
         .nest_service("/", ServeDir::new(config.paths.web_app))
-        .with_state(state)
+        .with_state(state.clone())
         .layer(ServiceBuilder::new().layer(axum::middleware::from_fn(add_cors_headers)))
         .layer(
             TraceLayer::new_for_http()
@@ -174,8 +244,107 @@ async fn main() -> anyhow::Result<()> {
         .into_make_service_with_connect_info::<SocketAddr>();
     log::info!("Listening on {address:?}");
     let listener = tokio::net::TcpListener::bind(address).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    // A tick that is running is finished, so it can't write after the final state.
+    stop_background.cancel();
+    if let Err(err) = ticker.await {
+        log::error!("Playlist tick task failed: {err:?}");
+    }
 
+    if let Some(archive_dir) = &state.archive_dir {
+        match state
+            .playlist
+            .archive_session(archive_dir, &state.index)
+            .await
+        {
+            Ok(path) => log::info!("Session archived to {path:?}"),
+            Err(err) => log::error!("Archiving the session failed: {err:?}"),
+        }
+    }
+    state.playlist.shutdown(&state.index).await?;
+
+    Ok(())
+}
+
+/// Reads all songs and the languages they're in from the database.
+fn load_song_db(path: &Path) -> anyhow::Result<(Vec<Song>, HashSet<String>)> {
+    let mut conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let tx = conn.transaction()?;
+
+    // Older databases don't have the explicit flag or the tempo yet.
+    let explicit_column = if tx.prepare("SELECT explicit FROM song LIMIT 0").is_ok() {
+        "explicit"
+    } else {
+        "0 AS explicit"
+    };
+    let bpm_column = if tx.prepare("SELECT bpm FROM song LIMIT 0").is_ok() {
+        "bpm"
+    } else {
+        "NULL AS bpm"
+    };
+    let mut stmt = tx.prepare(&format!(
+        "SELECT rowid, title, artist, language, year, duration, lyrics, player_count, {explicit_column}, cover_path, audio_path, {bpm_column} FROM song ORDER BY title COLLATE NOCASE",
+    ))?;
+    let mut lang_stmt =
+        tx.prepare("SELECT DISTINCT language FROM song WHERE LANGUAGE IS NOT NULL")?;
+    let song_db = stmt
+        .query_map((), |row| {
+            let row_id = row.get("rowid")?;
+            let cover_path = row.get::<_, Option<Vec<u8>>>("cover_path")?;
+            let audio_path = row.get::<_, Option<Vec<u8>>>("audio_path")?;
+            Ok(Song {
+                row_id,
+                title: row.get("title")?,
+                artist: row.get("artist")?,
+                language: row.get("language")?,
+                year: row.get("year")?,
+                duration: row.get("duration")?,
+                lyrics: row.get("lyrics")?,
+                duet: row.get::<_, i32>("player_count")? > 1,
+                explicit: row.get::<_, i32>("explicit")? != 0,
+                cover_path: cover_path.map(urlencode_path),
+                audio_path: urlencode_path(audio_path.unwrap()),
+                tempo: row.get("bpm")?,
+            })
+        })?
+        .filter_map(|result| match result {
+            Ok(song) => Some(song),
+            Err(err) => {
+                log::error!("Failed loading song: {err:?}");
+                None
+            }
+        })
+        .collect();
+    let languages = lang_stmt
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<Result<_, _>>()?;
+    Ok((song_db, languages))
+}
+
+/// Reloads the songs from the database, so new songs can be found and queued without a restart.
+async fn reload_catalog(state: &AppState) -> anyhow::Result<()> {
+    let database = state.database.clone();
+    let (song_db, languages) =
+        tokio::task::spawn_blocking(move || load_song_db(&database)).await??;
+    let index = state.index.clone();
+    let song_db = tokio::task::spawn_blocking(move || {
+        index.replace_songs(song_db.iter())?;
+        anyhow::Ok(song_db)
+    })
+    .await??;
+    state.song_count.store(song_db.len(), Ordering::Relaxed);
+    *state.languages.write().unwrap() = languages;
+    let dropped = state
+        .playlist
+        .refresh_valid_songs(song_db.iter().map(|song| song.row_id), &state.index)
+        .await?;
+    log::info!(
+        "Reloaded {} songs, dropped {dropped} entries of songs that are gone",
+        song_db.len()
+    );
     Ok(())
 }
 
@@ -197,7 +366,7 @@ async fn get_song(
             StatusCode::BAD_REQUEST
         })?;
 
-    let result = state.index.search(&ids.join(" OR ")).map_err(|err| {
+    let result = state.index.search(&ids.join(" OR "), None).map_err(|err| {
         log::error!("Search for songs {ids:?} failed: {err:?}");
         StatusCode::INTERNAL_SERVER_ERROR
     })?;
@@ -208,18 +377,63 @@ async fn get_song(
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct SearchOptions {
+    field: Option<SearchField>,
+}
+
 async fn search(
     State(state): State<Arc<AppState>>,
+    Query(SearchOptions { field }): Query<SearchOptions>,
     search_str: String,
 ) -> Result<Json<Vec<serde_json::Value>>, (StatusCode, Body)> {
-    log::debug!("Searching for {search_str:?}");
-    let result = state.index.search(&search_str).map_err(|err| {
+    log::debug!("Searching for {search_str:?} in {field:?}");
+    let result = state
+        .index
+        .search_with_timeout(search_str.clone(), field, SEARCH_TIMEOUT)
+        .await
+        .map_err(|err| {
+            log::error!("Search for {search_str:?} failed: {err:?}");
+            (StatusCode::BAD_REQUEST, Body::from(format!("{err}")))
+        })?;
+    Ok(Json(result))
+}
+
+async fn search_ids(
+    State(state): State<Arc<AppState>>,
+    search_str: String,
+) -> Result<Json<Vec<i64>>, (StatusCode, Body)> {
+    log::debug!("Searching ids for {search_str:?}");
+    let result = state.index.search_ids(&search_str, 50).map_err(|err| {
         log::error!("Search for {search_str:?} failed: {err:?}");
         (StatusCode::BAD_REQUEST, Body::from(format!("{err}")))
     })?;
     Ok(Json(result))
 }
 
+#[derive(Debug, Deserialize)]
+struct DurationRange {
+    /// In seconds.
+    min: f64,
+    max: f64,
+}
+
+async fn search_duration(
+    State(state): State<Arc<AppState>>,
+    Query(DurationRange { min, max }): Query<DurationRange>,
+    search_str: String,
+) -> Result<Json<Vec<Song>>, (StatusCode, Body)> {
+    log::debug!("Searching for {search_str:?} between {min}s and {max}s");
+    let result = state
+        .index
+        .search_song_duration_range(&search_str, min, max, 50)
+        .map_err(|err| {
+            log::error!("Search for {search_str:?} failed: {err:?}");
+            (StatusCode::BAD_REQUEST, Body::from(format!("{err}")))
+        })?;
+    Ok(Json(result))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Pagination {
     offset: u32,
@@ -239,7 +453,7 @@ async fn get_all_songs(
 }
 
 async fn get_song_count(State(state): State<Arc<AppState>>) -> String {
-    state.song_count.to_string()
+    state.song_count.load(Ordering::Relaxed).to_string()
 }
 
 #[derive(Debug, Deserialize)]
@@ -262,12 +476,276 @@ async fn get_random_songs(
     Ok(Json(result))
 }
 
+#[derive(Debug, Deserialize)]
+struct Limit {
+    count: usize,
+}
+
+async fn get_unplayed_songs(
+    State(state): State<Arc<AppState>>,
+    Query(Limit { count }): Query<Limit>,
+) -> Result<Json<Vec<Song>>, StatusCode> {
+    let result = state
+        .playlist
+        .unplayed_songs(&state.index, count.min(100))
+        .await
+        .map_err(|err| {
+            log::error!("Fetching unplayed songs failed: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(result))
+}
+
+#[derive(Serialize)]
+struct BuggySong {
+    #[serde(flatten)]
+    song: Song,
+    reports: usize,
+}
+
+async fn get_buggy_songs(
+    State(state): State<Arc<AppState>>,
+    Query(Limit { count }): Query<Limit>,
+) -> Result<Json<Vec<BuggySong>>, StatusCode> {
+    let top = state.playlist.top_buggy_songs(count.min(100)).await;
+    if top.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+    let query = top
+        .iter()
+        .map(|(song, _)| format!("rowid:{song}"))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let mut songs: HashMap<_, _> = state
+        .index
+        .search_song_blocking(query, top.len())
+        .await
+        .map_err(|err| {
+            log::error!("Fetching buggy songs failed: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .map(|song| (song.row_id, song))
+        .collect();
+    // Songs that were removed from the catalog since they were reported are skipped.
+    Ok(Json(
+        top.into_iter()
+            .filter_map(|(song, reports)| {
+                songs.remove(&song).map(|song| BuggySong { song, reports })
+            })
+            .collect(),
+    ))
+}
+
+async fn get_state(State(state): State<Arc<AppState>>) -> Json<PlaylistState> {
+    Json(state.playlist.full_state().await)
+}
+
+async fn get_fairness(State(state): State<Arc<AppState>>) -> Json<Vec<SingerStats>> {
+    Json(state.playlist.fairness_report().await)
+}
+
+async fn get_churn(State(state): State<Arc<AppState>>) -> Json<ChurnReport> {
+    Json(state.playlist.churn_report().await)
+}
+
+async fn get_pending(
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<(String, Vec<PlaylistEntry>)>> {
+    Json(
+        state
+            .playlist
+            .pending_by_singer()
+            .await
+            .into_iter()
+            .map(|(singer, entries)| {
+                (
+                    singer,
+                    entries.iter().map(PlaylistEntry::scrubbed).collect(),
+                )
+            })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct Window {
+    seconds: u64,
+}
+
+/// The entries queued in the last `seconds` that haven't started yet, newest first.
+async fn get_recently_added(
+    State(state): State<Arc<AppState>>,
+    Query(Window { seconds }): Query<Window>,
+) -> Json<Vec<PlaylistEntry>> {
+    Json(
+        state
+            .playlist
+            .recently_added(
+                Duration::from_secs(seconds)
+                    .try_into()
+                    .unwrap_or(time::Duration::MAX),
+            )
+            .await
+            .iter()
+            .map(PlaylistEntry::scrubbed)
+            .collect(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct Singer {
+    singer: String,
+}
+
+/// Returns the singer's queued entries with the seconds until they start.
+async fn get_singer_schedule(
+    State(state): State<Arc<AppState>>,
+    Query(Singer { singer }): Query<Singer>,
+) -> Json<Vec<(Uuid, f64)>> {
+    Json(
+        state
+            .playlist
+            .singer_schedule(&singer)
+            .await
+            .into_iter()
+            .map(|(id, wait)| (id, wait.as_seconds_f64()))
+            .collect(),
+    )
+}
+
+async fn get_analytics(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AnalyticsExport>, StatusCode> {
+    let result = state
+        .playlist
+        .analytics_export(&state.index)
+        .await
+        .map_err(|err| {
+            log::error!("Exporting analytics failed: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(result))
+}
+
 async fn get_languages(State(state): State<Arc<AppState>>) -> Json<Vec<String>> {
-    let mut languages: Vec<_> = state.languages.iter().cloned().collect();
+    let mut languages: Vec<_> = state.languages.read().unwrap().iter().cloned().collect();
     languages.sort();
     Json(languages)
 }
 
+#[derive(Debug, Deserialize)]
+struct SongId {
+    song: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Position {
+    position: usize,
+}
+
+/// Returns the predicted wait in seconds.
+async fn get_wait_at_position(
+    State(state): State<Arc<AppState>>,
+    Query(Position { position }): Query<Position>,
+) -> Result<String, StatusCode> {
+    let wait = state
+        .playlist
+        .wait_at_position(position, &state.index)
+        .await
+        .map_err(|err| {
+            log::error!("Predicting wait at position {position} failed: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(wait.whole_seconds().to_string())
+}
+
+async fn get_predict_position(
+    State(state): State<Arc<AppState>>,
+    Query(SongId { song }): Query<SongId>,
+) -> Result<String, StatusCode> {
+    let position = state
+        .playlist
+        .predict_position(song, &state.index)
+        .await
+        .map_err(|err| {
+            log::error!("Predicting position for song {song} failed: {err:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(position.to_string())
+}
+
+/// Returns the average measured duration in seconds, if the song was played this session.
+async fn get_actual_duration(
+    State(state): State<Arc<AppState>>,
+    Query(SongId { song }): Query<SongId>,
+) -> Json<Option<f64>> {
+    Json(
+        state
+            .playlist
+            .actual_duration(song)
+            .await
+            .map(|duration| duration.as_seconds_f64()),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct Since {
+    since: u64,
+}
+
+async fn get_changes(
+    State(state): State<Arc<AppState>>,
+    Query(Since { since }): Query<Since>,
+) -> Result<Json<ChangesSince>, StatusCode> {
+    let changes = state.playlist.changes_since(since).await.map_err(|err| {
+        log::error!("Fetching changes since version {since} failed: {err:?}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(changes))
+}
+
+#[derive(Debug, Deserialize)]
+struct PayloadFilter {
+    /// Only the next queued entries.
+    next: Option<usize>,
+}
+
+/// The playlist as listeners get it, byte for byte.
+async fn get_payload(
+    State(state): State<Arc<AppState>>,
+    Query(PayloadFilter { next }): Query<PayloadFilter>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    let filter = next.map_or(SubscriptionFilter::All, SubscriptionFilter::Next);
+    let payload = state.playlist.render_payload(&filter).await;
+    ([(header::CONTENT_TYPE, "application/json")], payload)
+}
+
+async fn get_payload_stats(State(state): State<Arc<AppState>>) -> Json<PayloadStats> {
+    Json(state.playlist.payload_stats().await)
+}
+
+#[derive(Debug, Deserialize)]
+struct Device {
+    device: String,
+}
+
+async fn get_last_singer(
+    State(state): State<Arc<AppState>>,
+    Query(Device { device }): Query<Device>,
+) -> Json<Option<String>> {
+    Json(state.playlist.last_singer(&device).await)
+}
+
+async fn health_check(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthStatus>) {
+    let status = state.playlist.health_check(&state.index).await;
+    if status.is_healthy() {
+        (StatusCode::OK, Json(status))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(status))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Suggest {
     name: String,
@@ -283,13 +761,10 @@ async fn suggest(
         title,
     }): Json<Suggest>,
 ) {
-    let mut suggest_log = state.suggest_log.lock().await;
     let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
     let record = StringRecord::from(vec![&timestamp, &name, &artist, &title]);
-    let mut writer = Writer::from_writer(Vec::new());
-    writer.write_record(&record).unwrap();
 
-    if let Err(err) = suggest_log.write_all(&writer.into_inner().unwrap()).await {
+    if let Err(err) = state.suggest_log.write_record(record).await {
         log::error!("Failed writing song log: {err:?}");
     }
 }
@@ -1,415 +1,5127 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering},
 };
 
-use csv::{StringRecord, Writer};
+use csv::StringRecord;
+use hmac::{Hmac, Mac};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use sha256::digest;
 use tantivy::time::OffsetDateTime;
 use time::{format_description::well_known::Rfc3339, Duration};
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{mpsc::UnboundedSender, Mutex, RwLock},
+    sync::{mpsc::UnboundedSender, RwLock},
 };
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::songs::SearchIndex;
+use crate::{
+    add_guard::AddGuard,
+    catalog::SongCatalog,
+    config::{
+        DurationUnit, EmptyCatalogPolicy, LogFailurePolicy, Queue, SimilarSongPolicy, SingerField,
+        SongLogColumn, TamperedPlaylistPolicy,
+    },
+    durations::DurationProvider,
+    log_sink::LogSink,
+    songs::{normalized_words, Song},
+};
 
-const MAX_PLAY_HISTORY: usize = 3;
+fn same_singer(singer1: &str, singer2: &str) -> bool {
+    singer1.trim().to_lowercase() == singer2.trim().to_lowercase()
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The HMAC-SHA256 of `data`, hex encoded.
+fn sign(key: &str, data: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC keys can have any length");
+    mac.update(data);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Songs that claim to be longer than this (in seconds) have a broken duration in the catalog.
+const MAX_SONG_DURATION: f64 = 60.0 * 60.0;
+/// Used for predictions instead of broken song durations.
+const FALLBACK_SONG_DURATION: f64 = 4.0 * 60.0;
+
+/// Breaks this long or longer aren't representative and don't count towards the average intermission.
+/// Note that this might include breaks between whole parties, so it could be months as well.
+const MAX_INTERMISSION: Duration = Duration::minutes(5);
+
+/// After this many consecutive failed writes, persistence is reported as failing.
+const FAILURE_THRESHOLD: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlaylistEntry {
     id: Uuid,
     song: i64,
-    singer: String,
+    /// Who queued the song. Files written before performers existed call this `singer`.
+    #[serde(alias = "singer")]
+    requested_by: String,
+    /// Who sings the song, if that's someone else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    performed_by: Option<String>,
+    /// A stable id of whoever queued the song (e.g. their login), which tells people apart better
+    /// than their name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    singer_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     password_hash: Option<String>,
     #[serde(with = "time::serde::rfc3339")]
     predicted_end: OffsetDateTime,
+    /// The entry is beyond the prediction horizon, so `predicted_end` is only a lower bound.
+    #[serde(default)]
+    later: bool,
+    /// When the entry was queued. Entries persisted before this existed count as added on load.
+    #[serde(default = "OffsetDateTime::now_utc", with = "time::serde::rfc3339")]
+    added_at: OffsetDateTime,
+    /// When the song actually started playing, if it did.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    started_at: Option<OffsetDateTime>,
+    /// Pinned entries can't be moved or removed (except by playing them).
+    #[serde(default)]
+    pinned: bool,
+    #[serde(default)]
+    status: EntryStatus,
+    /// Entries are queued ahead of those with a lower priority.
+    #[serde(default)]
+    priority: u8,
+    /// The entry moves to the front of the queue at this time.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    scheduled_for: Option<OffsetDateTime>,
+    /// Shown on the screen instead of the song and singer, e.g. for a birthday song.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display_label: Option<String>,
+    /// The entry keeps its position until this time while its payment is confirmed.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    held_until: Option<OffsetDateTime>,
+    /// The break before the entry started in seconds, if it counted towards the intermission average.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    intermission: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EntryStatus {
+    #[default]
+    Queued,
+    /// Only used when played entries are kept in the list. They stay at the front of the list.
+    Played,
+}
+
+impl PlaylistEntry {
+    fn new(
+        song: i64,
+        requested_by: String,
+        password_hash: Option<String>,
+        predicted_end: OffsetDateTime,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            song,
+            requested_by,
+            performed_by: None,
+            singer_id: None,
+            password_hash,
+            predicted_end,
+            later: false,
+            added_at: OffsetDateTime::now_utc(),
+            started_at: None,
+            pinned: false,
+            status: EntryStatus::Queued,
+            priority: 0,
+            scheduled_for: None,
+            display_label: None,
+            held_until: None,
+            intermission: None,
+        }
+    }
+
+    pub fn song(&self) -> i64 {
+        self.song
+    }
+
+    fn is_played(&self) -> bool {
+        self.status == EntryStatus::Played
+    }
+
+    /// Who sings the song.
+    pub fn performer(&self) -> &str {
+        self.performed_by.as_deref().unwrap_or(&self.requested_by)
+    }
+
+    fn singer(&self, field: SingerField) -> &str {
+        match field {
+            SingerField::RequestedBy => &self.requested_by,
+            SingerField::PerformedBy => self.performer(),
+        }
+    }
+
+    /// Whether both entries are sung by the same person. The singer ids are compared if both entries
+    /// have one, the names otherwise.
+    fn same_singer_as(&self, other: &PlaylistEntry, field: SingerField) -> bool {
+        match (self.singer_id(field), other.singer_id(field)) {
+            (Some(id), Some(other_id)) => id == other_id,
+            _ => same_singer(self.singer(field), other.singer(field)),
+        }
+    }
+
+    /// Whether the entry was queued by the singer, compared by singer id if both have one.
+    fn requested_by_singer(&self, singer: &str, singer_id: Option<&str>) -> bool {
+        match (self.singer_id.as_deref(), singer_id) {
+            (Some(id), Some(other_id)) => id == other_id,
+            _ => same_singer(&self.requested_by, singer),
+        }
+    }
+
+    /// The singer id only belongs to whoever queued the song, not to a different performer.
+    fn singer_id(&self, field: SingerField) -> Option<&str> {
+        match field {
+            SingerField::PerformedBy if self.performed_by.is_some() => None,
+            _ => self.singer_id.as_deref(),
+        }
+    }
+
+    /// A copy without the password hash and the singer id, for showing to others.
+    pub fn scrubbed(&self) -> Self {
+        Self {
+            password_hash: None,
+            singer_id: None,
+            ..self.clone()
+        }
+    }
+
+    /// Why this entry can't be moved, if it can't.
+    fn locked(&self) -> Option<EditResult> {
+        if self.is_played() {
+            Some(EditResult::Unchanged)
+        } else if self.pinned {
+            Some(EditResult::Pinned)
+        } else if self.held_until.is_some() {
+            Some(EditResult::Held)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    /// Towards the front of the queue.
+    Up,
+    /// Towards the end of the queue.
+    Down,
+}
+
+/// How the tempo of the queued songs should develop, see [`Playlist::reorder_by_energy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EnergyCurve {
+    /// From slow to fast.
+    RampUp,
+    /// From fast to slow, e.g. towards the end of the party.
+    RampDown,
+    /// Alternating between slow and fast songs.
+    Wave,
+}
+
+impl EnergyCurve {
+    /// Whether the song at `position` should be a fast one.
+    fn wants_fast(self, position: usize) -> bool {
+        match self {
+            Self::RampUp => false,
+            Self::RampDown => true,
+            Self::Wave => position % 2 == 1,
+        }
+    }
+}
+
+/// How often a song was played this session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayRecord {
+    count: usize,
+    #[serde(with = "time::serde::rfc3339")]
+    last_played: OffsetDateTime,
+    /// The measured durations of the plays in seconds, see [`InnerPlaylist::actual_duration`].
+    #[serde(default)]
+    actual_total: f64,
+    /// How many plays were measured.
+    #[serde(default)]
+    actual_count: usize,
+}
+
+/// An entry removed by [`Playlist::remove_singer`] that can still be restored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrashedEntry {
+    entry: PlaylistEntry,
+    /// Where in the list the entry was.
+    position: usize,
+    #[serde(with = "time::serde::rfc3339")]
+    removed_at: OffsetDateTime,
+}
+
+/// How much the queue was reordered this session.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Churn {
+    /// Entries moved to another place (including nudges).
+    moves: usize,
+    swaps: usize,
+    /// Longest waiting entries moved to the front, manually or automatically.
+    promotions: usize,
+    /// How many places entries moved in total.
+    distance: usize,
+}
+
+impl Churn {
+    fn record(&mut self, counter: fn(&mut Self) -> &mut usize, distance: usize) {
+        *counter(self) += 1;
+        self.distance += distance;
+    }
+
+    fn report(&self) -> ChurnReport {
+        let reorders = self.moves + self.swaps + self.promotions;
+        ChurnReport {
+            moves: self.moves,
+            swaps: self.swaps,
+            promotions: self.promotions,
+            average_position_change: if reorders == 0 {
+                0.0
+            } else {
+                self.distance as f64 / reorders as f64
+            },
+        }
+    }
+}
+
+/// A place in the queue held for a singer who picks their song later.
+#[derive(Debug, Clone)]
+struct Reservation {
+    id: Uuid,
+    code: String,
+    /// The entry the claimed song is queued after, `None` for the front of the queue.
+    after: Option<Uuid>,
+    expires_at: OffsetDateTime,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 struct InnerPlaylist {
+    /// Incremented on every change, so reconnecting clients can tell whether their copy is current.
+    #[serde(default)]
+    version: u64,
     play_history: VecDeque<PlaylistEntry>,
     list: VecDeque<PlaylistEntry>,
+    /// Last call: the queue is played out, but no new songs can be added.
+    #[serde(default)]
+    adds_closed: bool,
+    /// Written to the logs, so plays and bug reports can be grouped by event.
+    #[serde(default)]
+    session: Option<String>,
+    /// The performance whose song log row waits for a rating.
+    #[serde(default)]
+    unrated: Option<Uuid>,
     #[serde(skip, default)]
     listeners: HashMap<Uuid, UnboundedSender<String>>,
     intermission_duration: Duration,
     intermission_count: usize,
+    /// Every song played this session, keyed by song id. Unlike the history, this isn't trimmed.
+    #[serde(default)]
+    play_record: HashMap<i64, PlayRecord>,
+    /// How often each song has been reported as buggy, keyed by song id.
+    #[serde(default)]
+    bug_reports: HashMap<i64, usize>,
+    /// How often songs that aren't in the catalog were requested, keyed by song id.
+    #[serde(default)]
+    missing_requests: HashMap<i64, usize>,
+    /// Reorderings of the queue this session.
+    #[serde(default)]
+    churn: Churn,
+    /// Entries of removed singers, kept around for the configured grace period.
+    #[serde(default)]
+    trash: Vec<TrashedEntry>,
+    /// Consecutive failed attempts to write the playlist to disk.
+    #[serde(skip, default)]
+    persist_failures: usize,
+    /// Entries whose singer already got the heads-up that they're up soon.
+    #[serde(skip, default)]
+    warned: HashSet<Uuid>,
+    /// The entry the screen was last told to preload.
+    #[serde(skip, default)]
+    preloaded: Option<Uuid>,
+    /// The part of the playlist last sent to listeners, when that is limited.
+    #[serde(skip, default)]
+    last_window: Option<serde_json::Value>,
+    /// Bytes of the playlist messages broadcast since the start.
+    #[serde(skip, default)]
+    broadcast_bytes: usize,
+    #[serde(skip, default)]
+    broadcast_count: usize,
+    #[serde(skip, default)]
+    largest_broadcast: usize,
+    /// The state after the most recent changes, for computing deltas.
+    #[serde(skip, default)]
+    recent_versions: VecDeque<(u64, VersionSnapshot)>,
+    /// Changes in the write-ahead log since the last snapshot.
+    #[serde(skip, default)]
+    wal_records: usize,
+    /// The session state as of the last snapshot or write-ahead log record.
+    #[serde(skip, default)]
+    logged_state: Option<SessionState>,
+    /// Songs played since the longest waiting entry was last promoted automatically.
+    #[serde(skip, default)]
+    plays_since_promotion: usize,
+    /// Wrong passwords tried per entry and when the first of them was tried.
+    #[serde(skip, default)]
+    failed_attempts: HashMap<Uuid, (usize, OffsetDateTime)>,
+    /// Results of recent adds by their idempotency key.
+    #[serde(skip, default)]
+    recent_adds: HashMap<String, (OffsetDateTime, AddResult)>,
+    /// Slots that haven't been claimed yet. Not sent to listeners, since the codes are secret.
+    #[serde(skip, default)]
+    reservations: Vec<Reservation>,
+    /// The singer name last used on each device and when.
+    #[serde(skip, default)]
+    device_singers: HashMap<String, (String, OffsetDateTime)>,
 }
 
-#[derive(Debug)]
-pub struct Playlist {
-    valid_songs: HashSet<i64>,
-    song_queue: RwLock<InnerPlaylist>,
-    persist_path: PathBuf,
-    song_log: Option<Mutex<File>>,
-    bug_log: Mutex<File>,
-}
+/// Display labels are cut off after this many characters.
+const MAX_LABEL_LENGTH: usize = 80;
 
-impl Playlist {
-    pub async fn load(
-        path: impl AsRef<Path>,
-        valid_songs: impl IntoIterator<Item = i64>,
-        song_log: Option<impl AsRef<Path>>,
-        bug_log: impl AsRef<Path>,
-    ) -> anyhow::Result<Self> {
-        let song_log = if let Some(song_log) = song_log {
-            Some(Mutex::new(
-                OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(song_log)
-                    .await?,
-            ))
-        } else {
-            None
-        };
+/// Characters used in claim codes, without the ones that are easily confused.
+const CLAIM_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CLAIM_CODE_LENGTH: usize = 6;
 
-        let bug_log = Mutex::new(
-            OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(bug_log)
-                .await?,
-        );
+/// How many versions are kept around for [`Playlist::changes_since`].
+const RECENT_VERSIONS: usize = 16;
 
-        match File::open(&path).await {
-            Ok(mut f) => {
-                let mut data = Vec::new();
-                f.read_to_end(&mut data).await?;
-                let valid_songs: HashSet<_> = valid_songs.into_iter().collect();
-                let mut song_queue: InnerPlaylist = serde_json::from_slice(&data)?;
+/// How long an idempotency key passed to [`Playlist::add`] is remembered.
+const IDEMPOTENCY_TTL: Duration = Duration::minutes(10);
 
-                // Don't keep songs in the list that no longer exist.
-                song_queue
-                    .list
-                    .retain(|entry| valid_songs.contains(&entry.song));
-                song_queue
-                    .play_history
-                    .retain(|entry| valid_songs.contains(&entry.song));
-
-                Ok(Self {
-                    valid_songs,
-                    song_queue: RwLock::new(song_queue),
-                    persist_path: path.as_ref().to_owned(),
-                    song_log,
-                    bug_log,
-                })
-            }
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self {
-                valid_songs: valid_songs.into_iter().collect(),
-                song_queue: Default::default(),
-                persist_path: path.as_ref().to_owned(),
-                song_log,
-                bug_log,
-            }),
-            Err(err) => Err(err.into()),
+/// How many different missing songs are counted, requests for further ones are ignored.
+const MAX_MISSING_SONGS: usize = 500;
+
+/// How many changes are appended to the write-ahead log before a full snapshot is written again.
+const WAL_COMPACTION: usize = 100;
+
+#[derive(Debug, Clone)]
+struct VersionSnapshot {
+    play_history: Vec<PlaylistEntry>,
+    list: Vec<PlaylistEntry>,
+    adds_closed: bool,
+}
+
+impl VersionSnapshot {
+    fn of(inner: &InnerPlaylist) -> Self {
+        Self {
+            play_history: inner.play_history.iter().cloned().collect(),
+            list: inner.list.iter().cloned().collect(),
+            adds_closed: inner.adds_closed,
         }
     }
 
-    pub async fn subscribe(&self, listener: UnboundedSender<String>) -> anyhow::Result<Uuid> {
-        let mut queue = self.song_queue.write().await;
-        listener.send(serde_json::to_string(&*queue).unwrap())?;
-        let id = Uuid::new_v4();
-        queue.listeners.insert(id, listener);
-        Ok(id)
+    fn entries(&self) -> impl Iterator<Item = &PlaylistEntry> {
+        self.play_history.iter().chain(self.list.iter())
     }
 
-    pub async fn unsubscribe(&self, id: Uuid) {
-        let mut queue = self.song_queue.write().await;
-        queue.listeners.remove(&id);
+    fn diff(&self, newer: &VersionSnapshot) -> PlaylistDelta {
+        let old_entries: HashMap<_, _> = self.entries().map(|entry| (entry.id, entry)).collect();
+        let new_ids: HashSet<_> = newer.entries().map(|entry| entry.id).collect();
+        PlaylistDelta {
+            changed: newer
+                .entries()
+                .filter(|entry| old_entries.get(&entry.id).copied() != Some(*entry))
+                .cloned()
+                .collect(),
+            removed: old_entries
+                .keys()
+                .filter(|id| !new_ids.contains(id))
+                .copied()
+                .collect(),
+            play_history: newer.play_history.iter().map(|entry| entry.id).collect(),
+            list: newer.list.iter().map(|entry| entry.id).collect(),
+            adds_closed: newer.adds_closed,
+        }
     }
 
-    pub async fn add(
-        &self,
-        song: i64,
-        singer: String,
-        password: Option<String>,
-        index: &SearchIndex,
-    ) -> anyhow::Result<Option<Uuid>> {
-        if !self.valid_songs.contains(&song) {
-            return Ok(None);
+    /// The predictions that changed, if nothing else did since this version.
+    fn prediction_changes(&self, newer: &VersionSnapshot) -> Option<Vec<Prediction>> {
+        if self.play_history != newer.play_history
+            || self.list.len() != newer.list.len()
+            || self.adds_closed != newer.adds_closed
+        {
+            return None;
         }
-        let songs = index.search_song(&format!("rowid:{song}"), 1)?;
-        if songs.is_empty() {
-            log::error!("Can't find song that we should have!");
-            Err(anyhow::anyhow!("Can't find song"))
-        } else {
-            let mut queue = self.song_queue.write().await;
-            let predicted_end = if queue.list.is_empty() {
-                OffsetDateTime::now_utc()
-            } else {
-                queue.list[queue.list.len() - 1].predicted_end
-                    + Duration::seconds_f64(songs[0].duration)
-            };
-            let id = Uuid::new_v4();
-            let maybe_hash = password.map(|password| digest(&password));
-            queue.list.push_back(PlaylistEntry {
-                id,
-                singer,
-                password_hash: maybe_hash,
-                song,
-                predicted_end,
-            });
-            Self::did_change(&mut queue, &self.persist_path, index).await?;
-            Ok(Some(id))
+        let mut changes = Vec::new();
+        for (old, new) in self.list.iter().zip(&newer.list) {
+            let same_otherwise = PlaylistEntry {
+                predicted_end: new.predicted_end,
+                later: new.later,
+                ..old.clone()
+            } == *new;
+            if !same_otherwise {
+                return None;
+            }
+            if old.predicted_end != new.predicted_end || old.later != new.later {
+                changes.push(Prediction {
+                    id: new.id,
+                    predicted_end: new.predicted_end,
+                    later: new.later,
+                    seconds_until: None,
+                });
+            }
         }
+        Some(changes)
     }
+}
 
-    pub async fn play(&self, id: Uuid, index: &SearchIndex) -> anyhow::Result<bool> {
-        let mut queue = self.song_queue.write().await;
-        if let Some(entry) = queue
+/// A new prediction for an entry, sent instead of the whole playlist when nothing else changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Prediction {
+    id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    predicted_end: OffsetDateTime,
+    #[serde(default)]
+    later: bool,
+    /// Only with `relative_times`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seconds_until: Option<f64>,
+}
+
+/// What changed between two versions of the playlist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistDelta {
+    /// Entries that are new or were modified.
+    changed: Vec<PlaylistEntry>,
+    /// Entries that are gone.
+    removed: Vec<Uuid>,
+    /// The ids in the history, in order.
+    play_history: Vec<Uuid>,
+    /// The ids in the list, in order.
+    list: Vec<Uuid>,
+    #[serde(default)]
+    adds_closed: bool,
+}
+
+impl PlaylistDelta {
+    fn apply(self, inner: &mut InnerPlaylist) {
+        let mut entries: HashMap<_, _> = inner
+            .play_history
+            .drain(..)
+            .chain(inner.list.drain(..))
+            .chain(self.changed)
+            .map(|entry| (entry.id, entry))
+            .collect();
+        for id in &self.removed {
+            entries.remove(id);
+        }
+        inner.play_history = self
+            .play_history
+            .iter()
+            .filter_map(|id| entries.remove(id))
+            .collect();
+        inner.list = self
             .list
             .iter()
-            .enumerate()
-            .find_map(|(idx, entry)| (entry.id == id).then_some(idx))
-        {
-            match index.search_song(&format!("rowid:{}", queue.list[entry].song), 1) {
-                Err(err) => {
-                    log::error!("Fetching song for song log failed: {err:?}");
-                }
-                Ok(songs) => {
-                    if queue.play_history.len() >= MAX_PLAY_HISTORY {
-                        queue.play_history.pop_front();
-                    }
-                    let old_playing_idx =
-                        (!queue.play_history.is_empty()).then(|| queue.play_history.len() - 1);
+            .filter_map(|id| entries.remove(id))
+            .collect();
+        inner.adds_closed = self.adds_closed;
+    }
+}
 
-                    if let Some(new_playing) = queue.list.remove(entry) {
-                        queue.play_history.push_back(new_playing);
-                    }
+/// A line in the write-ahead log: the change that led to `version`.
+#[derive(Debug, Serialize, Deserialize)]
+struct WalRecord {
+    version: u64,
+    delta: PlaylistDelta,
+    /// Only when it changed since the previous record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state: Option<SessionState>,
+}
 
-                    // Update intermission record
-                    if let Some(old_playing) =
-                        old_playing_idx.and_then(|idx| queue.play_history.get(idx))
-                    {
-                        let duration = OffsetDateTime::now_utc() - old_playing.predicted_end;
-                        // Ignore breaks that are 5 minutes or longer, since those aren't representative.
-                        // Note that this might include breaks between whole parties, so it could be months as well.
-                        if duration < Duration::minutes(5) && duration.is_positive() {
-                            queue.intermission_count += 1;
-                            queue.intermission_duration += duration;
-                        }
-                    }
+/// The part of the playlist file besides the entries, which isn't covered by [`PlaylistDelta`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionState {
+    session: Option<String>,
+    unrated: Option<Uuid>,
+    intermission_duration: Duration,
+    intermission_count: usize,
+    play_record: HashMap<i64, PlayRecord>,
+    bug_reports: HashMap<i64, usize>,
+    missing_requests: HashMap<i64, usize>,
+    churn: Churn,
+    trash: Vec<TrashedEntry>,
+}
 
-                    // Update playlist and notify listeners
-                    Self::did_change(&mut queue, &self.persist_path, index).await?;
-
-                    // Write song log
-                    if let Some(song_log) = &self.song_log {
-                        let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
-                        if songs.is_empty() {
-                            log::error!("Can't write song log: song not found!");
-                        } else {
-                            let mut song_log = song_log.lock().await;
-                            let record = StringRecord::from(vec![
-                                &timestamp,
-                                &songs[0].artist,
-                                &songs[0].title,
-                            ]);
-                            let mut writer = Writer::from_writer(Vec::new());
-                            writer.write_record(&record).unwrap();
-
-                            if let Err(err) =
-                                song_log.write_all(&writer.into_inner().unwrap()).await
-                            {
-                                log::error!("Failed writing song log: {err:?}");
-                            }
-                        }
-                    }
-                }
-            }
-            Ok(true)
-        } else {
-            Ok(false)
+impl SessionState {
+    fn of(inner: &InnerPlaylist) -> Self {
+        Self {
+            session: inner.session.clone(),
+            unrated: inner.unrated,
+            intermission_duration: inner.intermission_duration,
+            intermission_count: inner.intermission_count,
+            play_record: inner.play_record.clone(),
+            bug_reports: inner.bug_reports.clone(),
+            missing_requests: inner.missing_requests.clone(),
+            churn: inner.churn.clone(),
+            trash: inner.trash.clone(),
         }
     }
 
-    fn find_song_in_queue(playlist: &VecDeque<PlaylistEntry>, id: Uuid) -> Option<usize> {
-        playlist
-            .iter()
-            .enumerate()
-            .find_map(|(idx, entry)| (entry.id == id).then_some(idx))
+    fn apply(self, inner: &mut InnerPlaylist) {
+        inner.session = self.session;
+        inner.unrated = self.unrated;
+        inner.intermission_duration = self.intermission_duration;
+        inner.intermission_count = self.intermission_count;
+        inner.play_record = self.play_record;
+        inner.bug_reports = self.bug_reports;
+        inner.missing_requests = self.missing_requests;
+        inner.churn = self.churn;
+        inner.trash = self.trash;
     }
+}
 
-    pub async fn remove(&self, id: Uuid, index: &SearchIndex) -> anyhow::Result<bool> {
-        let mut queue = self.song_queue.write().await;
-        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
-            queue.list.remove(queue_index);
-            Self::did_change(&mut queue, &self.persist_path, index).await?;
-            return Ok(true);
-        }
-        Ok(false)
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum ChangesSince {
+    UpToDate {
+        version: u64,
+    },
+    Delta {
+        version: u64,
+        delta: PlaylistDelta,
+    },
+    /// The requested version is unknown, so this is the whole playlist.
+    Snapshot {
+        version: u64,
+        playlist: serde_json::Value,
+    },
+}
+
+impl InnerPlaylist {
+    fn record_broadcast(&mut self, bytes: usize) {
+        self.broadcast_bytes += bytes;
+        self.broadcast_count += 1;
+        self.largest_broadcast = self.largest_broadcast.max(bytes);
     }
 
-    pub async fn remove_if_password_correct(
-        &self,
-        id: Uuid,
-        password: String,
-        index: &SearchIndex,
-    ) -> anyhow::Result<bool> {
-        let mut queue = self.song_queue.write().await;
-        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
-            if let Some(hash) = &queue.list[queue_index].password_hash {
-                if &digest(password) == hash {
-                    queue.list.remove(queue_index);
-                    Self::did_change(&mut queue, &self.persist_path, index).await?;
-                    return Ok(true);
-                }
+    fn remove(&mut self, id: Uuid) -> EditResult {
+        if let Some(queue_index) = Playlist::find_song_in_queue(&self.list, id) {
+            if self.list[queue_index].pinned {
+                return EditResult::Pinned;
             }
+            self.list.remove(queue_index);
+            return EditResult::Changed;
         }
-        Ok(false)
+        EditResult::Unchanged
     }
 
-    pub async fn swap(&self, id1: Uuid, id2: Uuid, index: &SearchIndex) -> anyhow::Result<bool> {
+    fn swap(&mut self, id1: Uuid, id2: Uuid) -> EditResult {
         if id1 == id2 {
-            return Ok(false);
+            return EditResult::Unchanged;
         }
-        let mut queue = self.song_queue.write().await;
-        if let Some(entry1) = queue
-            .list
-            .iter()
-            .enumerate()
-            .find_map(|(idx, entry)| (entry.id == id1).then_some(idx))
-        {
-            if let Some(entry2) = queue
-                .list
-                .iter()
-                .enumerate()
-                .find_map(|(idx, entry)| (entry.id == id2).then_some(idx))
-            {
-                queue.list.swap(entry1, entry2);
-                Self::did_change(&mut queue, &self.persist_path, index).await?;
-                return Ok(true);
+        if let Some(entry1) = Playlist::find_song_in_queue(&self.list, id1) {
+            if let Some(entry2) = Playlist::find_song_in_queue(&self.list, id2) {
+                if let Some(result) = self.list[entry1]
+                    .locked()
+                    .or_else(|| self.list[entry2].locked())
+                {
+                    return result;
+                }
+                self.list.swap(entry1, entry2);
+                self.churn
+                    .record(|churn| &mut churn.swaps, entry1.abs_diff(entry2));
+                return EditResult::Changed;
             }
         }
-        Ok(false)
+        EditResult::Unchanged
     }
 
-    pub async fn move_after(
-        &self,
-        id: Uuid,
-        after: Uuid,
-        index: &SearchIndex,
-    ) -> anyhow::Result<bool> {
+    fn move_after(&mut self, id: Uuid, after: Uuid) -> EditResult {
         if id == after {
-            return Ok(false);
+            return EditResult::Unchanged;
         }
-        let mut queue = self.song_queue.write().await;
-        if let Some(entry) = queue
-            .list
-            .iter()
-            .enumerate()
-            .find_map(|(idx, entry)| (entry.id == id).then_some(idx))
-        {
-            if let Some(after_entry) = queue
-                .list
-                .iter()
-                .enumerate()
-                .find_map(|(idx, entry)| (entry.id == after).then_some(idx))
-            {
-                if entry < after_entry {
-                    let entry = queue.list.remove(entry).unwrap();
-                    queue.list.insert(after_entry, entry);
-                } else {
-                    let entry = queue.list.remove(entry).unwrap();
-                    queue.list.insert(after_entry + 1, entry);
+        if let Some(entry) = Playlist::find_song_in_queue(&self.list, id) {
+            if let Some(after_entry) = Playlist::find_song_in_queue(&self.list, after) {
+                if let Some(result) = self.list[entry].locked() {
+                    return result;
                 }
-                Self::did_change(&mut queue, &self.persist_path, index).await?;
-                return Ok(true);
+                let distance = Playlist::keep_pins_in_place(&mut self.list, |list| {
+                    let moved = list.remove(entry).unwrap();
+                    if entry < after_entry {
+                        list.insert(after_entry, moved);
+                        after_entry - entry
+                    } else {
+                        list.insert(after_entry + 1, moved);
+                        entry - after_entry - 1
+                    }
+                });
+                self.churn.record(|churn| &mut churn.moves, distance);
+                return EditResult::Changed;
             }
         }
-        Ok(false)
+        EditResult::Unchanged
     }
 
-    pub async fn move_top(&self, id: Uuid, index: &SearchIndex) -> anyhow::Result<bool> {
-        let mut queue = self.song_queue.write().await;
-        if let Some(entry) = queue
-            .list
+    fn move_top(&mut self, id: Uuid) -> EditResult {
+        if let Some(entry) = Playlist::find_song_in_queue(&self.list, id) {
+            if let Some(result) = self.list[entry].locked() {
+                return result;
+            }
+            Playlist::keep_pins_in_place(&mut self.list, |list| {
+                let entry = list.remove(entry).unwrap();
+                list.push_front(entry);
+            });
+            self.churn.record(|churn| &mut churn.moves, entry);
+            return EditResult::Changed;
+        }
+        EditResult::Unchanged
+    }
+
+    /// The song played last, either from the history or (when played entries are kept) from the list.
+    fn now_playing(&self) -> Option<&PlaylistEntry> {
+        self.list
             .iter()
-            .enumerate()
-            .find_map(|(idx, entry)| (entry.id == id).then_some(idx))
+            .rev()
+            .find(|entry| entry.is_played())
+            .or_else(|| self.play_history.back())
+    }
+
+    /// The point in time the queue starts from, which is the predicted end of the song currently playing.
+    /// If that is already over (e.g. the queue ran empty a while ago), the queue starts now.
+    fn baseline(&self) -> OffsetDateTime {
+        let now = OffsetDateTime::now_utc();
+        self.now_playing()
+            .map(|entry| entry.predicted_end.max(now))
+            .unwrap_or(now)
+    }
+
+    /// The average time the song took this session, from its start until the next song started
+    /// without the average intermission.
+    fn actual_duration(&self, song: i64) -> Option<Duration> {
+        self.play_record
+            .get(&song)
+            .filter(|record| record.actual_count > 0)
+            .map(|record| Duration::seconds_f64(record.actual_total / record.actual_count as f64))
+    }
+
+    /// The playlist as sent to listeners, limited to `max_entries` queued entries and the
+    /// `max_history` most recently played ones (if set).
+    fn listener_payload(
+        &self,
+        max_entries: Option<usize>,
+        max_history: Option<usize>,
+        relative_times: bool,
+    ) -> serde_json::Result<String> {
+        if let Some(max_entries) = max_entries {
+            self.finish_payload(self.window(max_entries, max_history)?, relative_times)
+        } else if relative_times {
+            self.finish_payload(
+                serde_json::to_value(self.listener_view(max_history))?,
+                relative_times,
+            )
+        } else {
+            serde_json::to_string(&self.listener_view(max_history))
+        }
+    }
+
+    /// The playlist as listeners get to see it, with the `max_history` most recently played entries
+    /// of the history (if set). Short PINs are easily guessed from their hash, and singer ids are
+    /// stable ids of the users, so the entries are scrubbed.
+    fn listener_view(&self, max_history: Option<usize>) -> ListenerPlaylist {
+        let skipped = max_history.map_or(0, |max_history| {
+            self.play_history.len().saturating_sub(max_history)
+        });
+        ListenerPlaylist {
+            version: self.version,
+            play_history: self
+                .play_history
+                .iter()
+                .skip(skipped)
+                .map(PlaylistEntry::scrubbed)
+                .collect(),
+            list: self.list.iter().map(PlaylistEntry::scrubbed).collect(),
+            adds_closed: self.adds_closed,
+            intermission_duration: self.intermission_duration,
+            intermission_count: self.intermission_count,
+        }
+    }
+
+    /// The playlist without the version, with the list cut down to `max_entries` queued entries
+    /// (played entries that are kept in the list are always included) and its full length in `totalLength`.
+    fn window(
+        &self,
+        max_entries: usize,
+        max_history: Option<usize>,
+    ) -> serde_json::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self.listener_view(max_history))?;
+        if let Some(object) = value.as_object_mut() {
+            object.remove("version");
+            if let Some(list) = object.get_mut("list").and_then(|list| list.as_array_mut()) {
+                let played = self.list.iter().filter(|entry| entry.is_played()).count();
+                list.truncate(played + max_entries);
+            }
+            object.insert("totalLength".to_owned(), self.list.len().into());
+        }
+        Ok(value)
+    }
+
+    /// Adds the version and, with `relative_times`, the server time and the seconds from now
+    /// until each entry ends (`secondsUntil`), so clients don't have to trust their own clock.
+    fn finish_payload(
+        &self,
+        mut value: serde_json::Value,
+        relative_times: bool,
+    ) -> serde_json::Result<String> {
+        if let Some(object) = value.as_object_mut() {
+            object.insert("version".to_owned(), self.version.into());
+            if relative_times {
+                let now = OffsetDateTime::now_utc();
+                object.insert("serverNow".to_owned(), now.format(&Rfc3339).unwrap().into());
+                for (key, entries) in [("playHistory", &self.play_history), ("list", &self.list)] {
+                    let Some(values) = object.get_mut(key).and_then(|values| values.as_array_mut())
+                    else {
+                        continue;
+                    };
+                    // The history is cut down at the front, the list at the end.
+                    let skipped = if key == "playHistory" {
+                        entries.len().saturating_sub(values.len())
+                    } else {
+                        0
+                    };
+                    for (value, entry) in values.iter_mut().zip(entries.iter().skip(skipped)) {
+                        if let Some(value) = value.as_object_mut() {
+                            value.insert(
+                                "secondsUntil".to_owned(),
+                                (entry.predicted_end - now).as_seconds_f64().into(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        serde_json::to_string(&value)
+    }
+
+    /// The message telling listeners that only these predictions changed.
+    fn predictions_payload(
+        &self,
+        mut predictions: Vec<Prediction>,
+        relative_times: bool,
+    ) -> serde_json::Result<String> {
+        if relative_times {
+            let now = OffsetDateTime::now_utc();
+            for prediction in &mut predictions {
+                prediction.seconds_until = Some((prediction.predicted_end - now).as_seconds_f64());
+            }
+        }
+        serde_json::to_string(&Event::Predictions {
+            version: self.version,
+            predictions,
+        })
+    }
+
+    /// The result of an earlier add with the same idempotency key. Forgets about expired keys.
+    fn repeated_add(&mut self, key: &str) -> Option<AddResult> {
+        let cutoff = OffsetDateTime::now_utc() - IDEMPOTENCY_TTL;
+        self.recent_adds
+            .retain(|_, (added_at, _)| *added_at >= cutoff);
+        self.recent_adds.get(key).map(|(_, result)| *result)
+    }
+
+    fn state(&self) -> PlaylistState {
+        PlaylistState {
+            version: self.version,
+            play_history: self
+                .play_history
+                .iter()
+                .map(PlaylistEntry::scrubbed)
+                .collect(),
+            list: self.list.iter().map(PlaylistEntry::scrubbed).collect(),
+            average_intermission: self.average_intermission().as_seconds_f64(),
+        }
+    }
+
+    fn average_intermission(&self) -> Duration {
+        self.intermission_duration
+            .checked_div(self.intermission_count as _)
+            .unwrap_or_default()
+    }
+}
+
+/// A read-only copy of a playlist on another server, kept in sync with the messages it sends to listeners.
+#[derive(Debug, Default)]
+pub struct PlaylistFollower {
+    inner: InnerPlaylist,
+    /// Whether a playlist was received yet.
+    synced: bool,
+    /// The length of the list on the primary, when only part of it is sent.
+    total_length: Option<usize>,
+}
+
+impl PlaylistFollower {
+    /// Takes over the playlist from a message sent to listeners, other messages are ignored.
+    /// Returns whether the playlist changed.
+    pub fn apply_broadcast(&mut self, message: &str) -> serde_json::Result<bool> {
+        let value: serde_json::Value = serde_json::from_str(message)?;
+        if value.get("event").and_then(|event| event.as_str()) == Some("predictions") {
+            return self.apply_predictions(value);
+        } else if value.get("event").is_some() {
+            return Ok(false);
+        }
+        let total_length = value
+            .get("totalLength")
+            .and_then(|length| length.as_u64())
+            .map(|length| length as usize);
+        let inner: InnerPlaylist = serde_json::from_value(value)?;
+        let changed = !self.synced || inner.version != self.inner.version;
+        self.inner = inner;
+        self.synced = true;
+        self.total_length = total_length;
+        Ok(changed)
+    }
+
+    fn apply_predictions(&mut self, value: serde_json::Value) -> serde_json::Result<bool> {
+        #[derive(Deserialize)]
+        struct Update {
+            version: u64,
+            predictions: Vec<Prediction>,
+        }
+        let update: Update = serde_json::from_value(value)?;
+        if !self.synced {
+            return Ok(false);
+        }
+        for prediction in update.predictions {
+            if let Some(entry) = self
+                .inner
+                .list
+                .iter_mut()
+                .find(|entry| entry.id == prediction.id)
+            {
+                entry.predicted_end = prediction.predicted_end;
+                entry.later = prediction.later;
+            }
+        }
+        self.inner.version = update.version;
+        Ok(true)
+    }
+
+    /// The version of the mirrored playlist, `None` before the first one was received.
+    pub fn version(&self) -> Option<u64> {
+        self.synced.then_some(self.inner.version)
+    }
+
+    pub fn now_playing(&self) -> Option<&PlaylistEntry> {
+        self.inner.now_playing()
+    }
+
+    /// The next entry to be played.
+    pub fn up_next(&self) -> Option<&PlaylistEntry> {
+        self.inner.list.iter().find(|entry| !entry.is_played())
+    }
+
+    /// The length of the list on the primary, even if only part of it was sent.
+    pub fn len(&self) -> usize {
+        self.total_length.unwrap_or(self.inner.list.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn state(&self) -> PlaylistState {
+        self.inner.state()
+    }
+}
+
+/// Messages sent to listeners in addition to the playlist state.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase", tag = "event")]
+pub enum Event {
+    /// The entry is predicted to start soon.
+    UpSoon { id: Uuid, singer: String },
+    /// The current song is about to end, the screen can load the next one.
+    Preload { id: Uuid, song: Song },
+    /// Sent instead of the playlist on subscribe when the client already has this version.
+    UpToDate { version: u64 },
+    /// Writing the playlist to disk failed repeatedly, changes will be lost on restart.
+    PersistenceFailing { failures: usize },
+    /// Only the predictions of these entries changed, everything else is as in the previous version.
+    Predictions {
+        version: u64,
+        predictions: Vec<Prediction>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AddResult {
+    Added(Uuid),
+    /// The song doesn't exist in the catalog.
+    UnknownSong,
+    /// No new songs are accepted, see [`Playlist::set_accepting_adds`].
+    AddsClosed,
+    /// There is no reservation with this claim code (anymore).
+    UnknownClaimCode,
+    /// The song would have to wait longer than allowed.
+    QueueTooLong,
+    /// The song is marked as explicit and the party is family friendly.
+    Explicit,
+    /// The add guard rejected the request.
+    Unverified,
+    /// The waiting entry with this id is (almost) the same song.
+    Similar(Uuid),
+    /// Added, but the waiting entry `similar` is (almost) the same song.
+    AddedSimilar {
+        id: Uuid,
+        similar: Uuid,
+    },
+    /// Added, but the waiting entry `other` has the same singer name and a different password,
+    /// so it might be someone else.
+    AddedSameName {
+        id: Uuid,
+        other: Uuid,
+    },
+}
+
+/// A reserved slot and the code to claim it with.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReservedSlot {
+    /// The id the entry will have once the slot is claimed.
+    pub id: Uuid,
+    pub code: String,
+}
+
+/// A played song as written to the session archive.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchivedPlay {
+    singer: String,
+    #[serde(with = "time::serde::rfc3339::option")]
+    started_at: Option<OffsetDateTime>,
+    song: i64,
+    /// Missing if the song is no longer in the catalog.
+    artist: Option<String>,
+    title: Option<String>,
+}
+
+/// Summary of a session, written by [`Playlist::archive_session`].
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionArchive {
+    #[serde(with = "time::serde::rfc3339")]
+    archived_at: OffsetDateTime,
+    /// The played songs that are still known, oldest first.
+    history: Vec<ArchivedPlay>,
+    /// All songs played this session, including the ones no longer in the history.
+    songs_played: usize,
+    distinct_songs: usize,
+    /// Entries that were still waiting when the archive was written.
+    still_queued: usize,
+    bug_reports: usize,
+    /// Average break between two songs in seconds.
+    average_intermission: f64,
+    intermission_count: usize,
+}
+
+/// How a singer fared so far this session.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SingerStats {
+    /// The name as it was first entered, other spellings are counted as the same singer.
+    pub singer: String,
+    pub songs_played: usize,
+    pub songs_pending: usize,
+    /// Seconds spent waiting, until the song started or until now if it's still queued.
+    pub total_wait: f64,
+}
+
+/// How much the queue was reordered this session, a lot of it can mean people are cutting the line.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChurnReport {
+    pub moves: usize,
+    pub swaps: usize,
+    pub promotions: usize,
+    /// How many places an entry moved per reordering on average.
+    pub average_position_change: f64,
+}
+
+/// The playlist as listeners get to see it. Only what is listed here is sent, so what is kept for
+/// the host (bug reports, the trash, the churn, …) stays private when new fields are added.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ListenerPlaylist {
+    version: u64,
+    play_history: Vec<PlaylistEntry>,
+    list: Vec<PlaylistEntry>,
+    adds_closed: bool,
+    intermission_duration: Duration,
+    intermission_count: usize,
+}
+
+/// Which part of the playlist a listener is sent, see [`Playlist::render_payload`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubscriptionFilter {
+    /// The playlist as every listener gets it, cut down to `max_broadcast_entries` if that is set.
+    #[default]
+    All,
+    /// Only the next queued entries, e.g. for a small display next to the stage.
+    Next(usize),
+}
+
+/// A copy of the playlist with the password hashes removed.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistState {
+    pub version: u64,
+    pub play_history: Vec<PlaylistEntry>,
+    pub list: Vec<PlaylistEntry>,
+    /// Average break between two songs in seconds.
+    pub average_intermission: f64,
+}
+
+impl PlaylistState {
+    /// What changed in the list from this state to `newer`, e.g. to animate the change.
+    pub fn diff(&self, newer: &PlaylistState) -> PlaylistDiff {
+        let old_positions: HashMap<_, _> = self
+            .list
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| (entry.id, idx))
+            .collect();
+        let new_ids: HashSet<_> = newer.list.iter().map(|entry| entry.id).collect();
+        let mut diff = PlaylistDiff {
+            removed: self
+                .list
+                .iter()
+                .filter(|entry| !new_ids.contains(&entry.id))
+                .map(|entry| entry.id)
+                .collect(),
+            ..Default::default()
+        };
+
+        // Entries in both lists as (old position, new position), in the new order.
+        let mut kept = Vec::new();
+        for (idx, entry) in newer.list.iter().enumerate() {
+            let Some(&old_idx) = old_positions.get(&entry.id) else {
+                diff.added.push(entry.clone());
+                continue;
+            };
+            kept.push((old_idx, idx));
+            let old = &self.list[old_idx];
+            if old.predicted_end != entry.predicted_end || old.later != entry.later {
+                diff.predictions.push(Prediction {
+                    id: entry.id,
+                    predicted_end: entry.predicted_end,
+                    later: entry.later,
+                    seconds_until: None,
+                });
+            }
+        }
+
+        // The longest run of entries that kept their order didn't move, everything else did.
+        // `run_lengths[i]` is the length of the longest such run ending with `kept[i]`.
+        let mut run_lengths = vec![1; kept.len()];
+        let mut previous = vec![None; kept.len()];
+        for i in 0..kept.len() {
+            for j in 0..i {
+                if kept[j].0 < kept[i].0 && run_lengths[j] + 1 > run_lengths[i] {
+                    run_lengths[i] = run_lengths[j] + 1;
+                    previous[i] = Some(j);
+                }
+            }
+        }
+        let mut in_order = vec![false; kept.len()];
+        let mut current = (0..kept.len()).max_by_key(|&i| run_lengths[i]);
+        while let Some(i) = current {
+            in_order[i] = true;
+            current = previous[i];
+        }
+        diff.moved = kept
+            .iter()
+            .zip(in_order)
+            .filter(|(_, in_order)| !in_order)
+            .map(|(&(from, to), _)| MovedEntry {
+                id: newer.list[to].id,
+                from,
+                to,
+            })
+            .collect();
+        diff
+    }
+}
+
+/// What changed in the list between two states, see [`PlaylistState::diff`].
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistDiff {
+    /// Entries that are new in the list.
+    pub added: Vec<PlaylistEntry>,
+    /// Entries that are no longer in the list, because they were played or removed.
+    pub removed: Vec<Uuid>,
+    /// Entries that changed their place relative to the others.
+    pub moved: Vec<MovedEntry>,
+    /// Entries in both lists whose prediction changed.
+    pub predictions: Vec<Prediction>,
+}
+
+/// An entry that moved from one position in the list to another.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MovedEntry {
+    pub id: Uuid,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// How often a song was played, without anything about who sang it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SongPlays {
+    pub artist: String,
+    pub title: String,
+    pub plays: usize,
+}
+
+/// Usage statistics that can be shared, they contain nothing about the singers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyticsExport {
+    pub songs_played: usize,
+    pub distinct_songs: usize,
+    /// The most played songs, most played first.
+    pub top_songs: Vec<SongPlays>,
+    /// Average break between two songs in seconds.
+    pub average_intermission: f64,
+    pub intermission_count: usize,
+    pub songs_queued: usize,
+}
+
+/// How many songs are listed in [`AnalyticsExport::top_songs`].
+const TOP_SONGS: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayResult {
+    /// There is nothing left in the queue.
+    QueueEmpty,
+    Played(Box<PlaylistEntry>),
+}
+
+/// A play from another system, for [`Playlist::seed_intermissions`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoricalPlay {
+    #[serde(with = "time::serde::rfc3339")]
+    pub start: OffsetDateTime,
+    /// In seconds.
+    pub duration: f64,
+}
+
+/// Everything about an add besides the song and the singer, see [`Playlist::add`].
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    /// Needed to remove the entry again. Without one, only admins can remove it.
+    pub password: Option<String>,
+    /// Passed on to the add guard.
+    pub token: Option<String>,
+    pub priority: u8,
+    /// Retrying an add with the same key returns the earlier result instead of adding the song again.
+    pub idempotency_key: Option<String>,
+    /// The singer is remembered for this device, see [`Playlist::last_singer`].
+    pub device: Option<String>,
+    /// Who sings the song, if that's not the one adding it.
+    pub performed_by: Option<String>,
+    /// A stable id of the one adding the song, if the client knows one.
+    pub singer_id: Option<String>,
+}
+
+/// A song to add with [`Playlist::add_many`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkAdd {
+    pub song: i64,
+    pub singer: String,
+}
+
+/// The outcome of a bulk operation for each item, in the order they were given.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkReport {
+    pub added: usize,
+    pub rejected: usize,
+    pub results: Vec<AddResult>,
+}
+
+impl AddResult {
+    /// Notes refused adds and adds that might be a mistake in the log.
+    pub fn log(&self, who: impl std::fmt::Debug, song: i64) {
+        match self {
+            AddResult::Explicit => log::info!("[{who:?}] Refused to add explicit song {song}"),
+            AddResult::Unverified => {
+                log::info!("[{who:?}] Refused to add unverified request for song {song}")
+            }
+            AddResult::AddsClosed => {
+                log::info!("[{who:?}] Refused to add song {song}, the queue is closed")
+            }
+            AddResult::QueueTooLong => {
+                log::info!("[{who:?}] Refused to add song {song}, the queue is too long")
+            }
+            AddResult::Similar(similar) => {
+                log::info!("[{who:?}] Refused to add song {song}, {similar} is the same song")
+            }
+            AddResult::AddedSimilar { similar, .. } => {
+                log::info!("[{who:?}] Added song {song} although {similar} is the same song")
+            }
+            AddResult::AddedSameName { other, .. } => {
+                log::info!("[{who:?}] Added song {song}, {other} has the same singer name")
+            }
+            _ => {}
+        }
+    }
+}
+
+impl BulkReport {
+    fn new(results: Vec<AddResult>) -> Self {
+        let added = results
+            .iter()
+            .filter(|result| {
+                matches!(
+                    result,
+                    AddResult::Added(_)
+                        | AddResult::AddedSimilar { .. }
+                        | AddResult::AddedSameName { .. }
+                )
+            })
+            .count();
+        Self {
+            added,
+            rejected: results.len() - added,
+            results,
+        }
+    }
+}
+
+/// A reordering that is part of a [`Playlist::batch`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum QueueOp {
+    MoveTop { id: Uuid },
+    MoveAfter { id: Uuid, after: Uuid },
+    Swap { id1: Uuid, id2: Uuid },
+    Remove { id: Uuid },
+}
+
+/// The outcome of a [`Playlist::batch`].
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResult {
+    /// Whether the operations were applied, which they are only if each of them changed something.
+    pub applied: bool,
+    /// The outcome of each operation in order, up to the first one that didn't change anything.
+    pub results: Vec<EditResult>,
+}
+
+/// The outcome of an edit to an existing entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EditResult {
+    Changed,
+    /// The entry wasn't found or the edit doesn't apply to it.
+    Unchanged,
+    /// The entry is pinned and has to be unpinned first.
+    Pinned,
+    /// The entry's position is held until its payment is confirmed.
+    Held,
+    /// Too many wrong passwords were tried for the entry, try again later.
+    Locked,
+}
+
+/// How large the playlist messages sent to listeners are.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadStats {
+    /// Bytes of the playlist a new listener would be sent now.
+    pub current: usize,
+    /// The largest broadcast since the start.
+    pub largest: usize,
+    pub average: f64,
+    pub broadcasts: usize,
+}
+
+/// Which of the subsystems the playlist depends on are currently usable.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthStatus {
+    pub persist: bool,
+    pub song_log: bool,
+    pub bug_log: bool,
+    pub index: bool,
+    /// How many songs the search index holds.
+    pub song_count: usize,
+    /// Consecutive failed writes of the playlist file.
+    pub persist_failures: usize,
+    /// Consecutive failed writes to the song or bug log.
+    pub log_failures: usize,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.persist
+            && self.song_log
+            && self.bug_log
+            && self.index
+            && self.persist_failures < FAILURE_THRESHOLD
+            && self.log_failures < FAILURE_THRESHOLD
+    }
+}
+
+#[derive(Debug)]
+pub struct Playlist {
+    valid_songs: RwLock<HashSet<i64>>,
+    /// The highest id in `valid_songs`, requests for songs above it can't be for a missing song.
+    max_song_id: AtomicI64,
+    song_queue: RwLock<InnerPlaylist>,
+    persist_path: PathBuf,
+    song_log: Option<Box<dyn LogSink>>,
+    bug_log: Box<dyn LogSink>,
+    add_guard: Option<Box<dyn AddGuard>>,
+    durations: Option<Box<dyn DurationProvider>>,
+    /// Consecutive failed writes to the song or bug log.
+    log_failures: AtomicUsize,
+    /// Set once the final state was written, later changes aren't persisted anymore.
+    shut_down: AtomicBool,
+    settings: Queue,
+}
+
+impl Playlist {
+    pub async fn load(
+        path: impl AsRef<Path>,
+        valid_songs: impl IntoIterator<Item = i64>,
+        song_log: Option<Box<dyn LogSink>>,
+        bug_log: Box<dyn LogSink>,
+        add_guard: Option<Box<dyn AddGuard>>,
+        durations: Option<Box<dyn DurationProvider>>,
+        mut settings: Queue,
+    ) -> anyhow::Result<Self> {
+        // The last entry of the history is the song currently playing, so we need room for at least that one.
+        settings.max_play_history = settings.max_play_history.max(1);
+        // Only the kept history can be shown, older plays are just counted.
+        if let Some(shown) = settings
+            .shown_play_history
+            .filter(|shown| *shown > settings.max_play_history)
+        {
+            log::warn!(
+                "shown_play_history ({shown}) is larger than max_play_history, showing {} songs",
+                settings.max_play_history
+            );
+            settings.shown_play_history = Some(settings.max_play_history);
+        }
+
+        let valid_songs: HashSet<_> = valid_songs.into_iter().collect();
+        if valid_songs.is_empty() {
+            match settings.empty_catalog {
+                EmptyCatalogPolicy::Warn => log::warn!(
+                    "The song catalog is empty, no songs can be added until it is reloaded!"
+                ),
+                EmptyCatalogPolicy::Fail => {
+                    return Err(anyhow::anyhow!("The song catalog is empty"));
+                }
+            }
+        }
+
+        let song_queue = match File::open(&path).await {
+            Ok(mut f) => {
+                let mut data = Vec::new();
+                f.read_to_end(&mut data).await?;
+                // Signed files start with the signature on a line of its own.
+                let newline = data.iter().position(|&byte| byte == b'\n');
+                let playlist = match (&settings.persist_key, newline) {
+                    (Some(key), Some(newline))
+                        if data[..newline] == *sign(key, &data[newline + 1..]).as_bytes() =>
+                    {
+                        Some(&data[newline + 1..])
+                    }
+                    (Some(_), _) => None,
+                    // Signed while a key was configured.
+                    (None, Some(newline)) if !data.starts_with(b"{") => Some(&data[newline + 1..]),
+                    (None, _) => Some(&data[..]),
+                };
+                let wal_path = Self::wal_path(path.as_ref());
+                let mut song_queue = if let Some(playlist) = playlist {
+                    let mut song_queue: InnerPlaylist = serde_json::from_slice(playlist)?;
+                    Self::replay_wal(&wal_path, &mut song_queue, settings.persist_key.as_deref())
+                        .await?;
+                    song_queue
+                } else {
+                    let message = format!(
+                        "The signature of {:?} doesn't match, it might have been edited by hand",
+                        path.as_ref()
+                    );
+                    if settings.tampered_playlist == TamperedPlaylistPolicy::Refuse {
+                        return Err(anyhow::anyhow!(message));
+                    }
+                    let aside = path.as_ref().with_extension("tampered");
+                    log::error!("{message}! Moving it to {aside:?} and starting over.");
+                    tokio::fs::rename(&path, &aside).await?;
+                    // The log belongs to the discarded state, replaying it on an empty list makes no sense.
+                    match tokio::fs::rename(&wal_path, aside.with_extension("tampered.wal")).await {
+                        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(err.into())
+                        }
+                        _ => {}
+                    }
+                    InnerPlaylist::default()
+                };
+
+                // Don't keep songs in the list that no longer exist. An empty catalog most likely
+                // failed to load though, that doesn't mean all the queued songs are gone.
+                if !valid_songs.is_empty() {
+                    let list_len = song_queue.list.len();
+                    song_queue
+                        .list
+                        .retain(|entry| valid_songs.contains(&entry.song));
+                    if song_queue.list.len() != list_len {
+                        song_queue.version += 1;
+                    }
+                    song_queue
+                        .play_history
+                        .retain(|entry| valid_songs.contains(&entry.song));
+                }
+                // The history cap might have been lowered since the file was written.
+                while song_queue.play_history.len() > settings.max_play_history {
+                    song_queue.play_history.pop_front();
+                }
+                song_queue
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let max_song_id = valid_songs.iter().copied().max().unwrap_or(0);
+        Ok(Self {
+            valid_songs: RwLock::new(valid_songs),
+            max_song_id: AtomicI64::new(max_song_id),
+            song_queue: RwLock::new(song_queue),
+            persist_path: path.as_ref().to_owned(),
+            song_log,
+            bug_log,
+            add_guard,
+            durations,
+            log_failures: AtomicUsize::new(0),
+            shut_down: AtomicBool::new(false),
+            settings,
+        })
+    }
+
+    /// `known_version` is the version of the playlist the client still has from an earlier connection.
+    pub async fn subscribe(
+        &self,
+        listener: UnboundedSender<String>,
+        known_version: Option<u64>,
+    ) -> anyhow::Result<Uuid> {
+        // Rendering the snapshot only needs the read lock, so clients reconnecting in quick succession
+        // don't block each other or the playlist for long.
+        let sent_version = {
+            let queue = self.song_queue.read().await;
+            self.send_initial(&queue, &listener, known_version)?;
+            queue.version
+        };
+        let mut queue = self.song_queue.write().await;
+        if queue.version != sent_version {
+            // Changed in between, the broadcast went out before we were listening.
+            self.send_initial(&queue, &listener, Some(sent_version))?;
+        }
+        let id = Uuid::new_v4();
+        queue.listeners.insert(id, listener);
+        Ok(id)
+    }
+
+    fn send_initial(
+        &self,
+        queue: &InnerPlaylist,
+        listener: &UnboundedSender<String>,
+        known_version: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if known_version == Some(queue.version) {
+            listener.send(serde_json::to_string(&Event::UpToDate {
+                version: queue.version,
+            })?)?;
+        } else {
+            listener.send(self.render(queue, SubscriptionFilter::All)?)?;
+        }
+        Ok(())
+    }
+
+    /// For clients that poll instead of listening: what changed since the version they know.
+    pub async fn changes_since(&self, version: u64) -> anyhow::Result<ChangesSince> {
+        let queue = self.song_queue.read().await;
+        if version == queue.version {
+            return Ok(ChangesSince::UpToDate { version });
+        }
+        if let Some((_, known)) = queue
+            .recent_versions
+            .iter()
+            .find(|(recent_version, _)| *recent_version == version)
+        {
+            let mut delta = known.diff(&VersionSnapshot::of(&queue));
+            for entry in &mut delta.changed {
+                *entry = entry.scrubbed();
+            }
+            return Ok(ChangesSince::Delta {
+                version: queue.version,
+                delta,
+            });
+        }
+        Ok(ChangesSince::Snapshot {
+            version: queue.version,
+            playlist: serde_json::to_value(queue.listener_view(None))?,
+        })
+    }
+
+    /// Exactly what a new listener with the filter is sent as the playlist, e.g. to know how large the
+    /// messages are.
+    pub async fn render_payload(&self, filter: &SubscriptionFilter) -> String {
+        let queue = self.song_queue.read().await;
+        self.render(&queue, *filter)
+            .expect("the listener view only holds plain data")
+    }
+
+    /// The playlist message for listeners with the filter, also used for broadcasting changes.
+    fn render(
+        &self,
+        queue: &InnerPlaylist,
+        filter: SubscriptionFilter,
+    ) -> serde_json::Result<String> {
+        let max_entries = match filter {
+            SubscriptionFilter::All => self.settings.max_broadcast_entries,
+            SubscriptionFilter::Next(next) => Some(
+                self.settings
+                    .max_broadcast_entries
+                    .map_or(next, |max_entries| max_entries.min(next)),
+            ),
+        };
+        queue.listener_payload(
+            max_entries,
+            self.settings.shown_play_history,
+            self.settings.relative_times,
+        )
+    }
+
+    /// Bytes of the playlist a new listener would be sent now.
+    pub async fn payload_size(&self) -> usize {
+        self.render_payload(&SubscriptionFilter::All).await.len()
+    }
+
+    pub async fn payload_stats(&self) -> PayloadStats {
+        let current = self.payload_size().await;
+        let queue = self.song_queue.read().await;
+        PayloadStats {
+            current,
+            largest: queue.largest_broadcast,
+            average: queue.broadcast_bytes as f64 / queue.broadcast_count.max(1) as f64,
+            broadcasts: queue.broadcast_count,
+        }
+    }
+
+    pub async fn full_state(&self) -> PlaylistState {
+        self.song_queue.read().await.state()
+    }
+
+    /// Replaces the set of songs that can be queued, e.g. after the catalog was reloaded.
+    /// Entries of songs that no longer exist are dropped, returns how many.
+    pub async fn refresh_valid_songs(
+        &self,
+        valid_songs: impl IntoIterator<Item = i64>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<usize> {
+        let valid_songs: HashSet<_> = valid_songs.into_iter().collect();
+        let mut queue = self.song_queue.write().await;
+        let before = queue.list.len() + queue.play_history.len();
+        queue.list.retain(|entry| valid_songs.contains(&entry.song));
+        queue
+            .play_history
+            .retain(|entry| valid_songs.contains(&entry.song));
+        let dropped = before - queue.list.len() - queue.play_history.len();
+        self.max_song_id.store(
+            valid_songs.iter().copied().max().unwrap_or(0),
+            Ordering::Relaxed,
+        );
+        *self.valid_songs.write().await = valid_songs;
+        if dropped > 0 {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(dropped)
+    }
+
+    pub async fn unsubscribe(&self, id: Uuid) {
+        let mut queue = self.song_queue.write().await;
+        queue.listeners.remove(&id);
+    }
+
+    pub async fn add(
+        &self,
+        song: i64,
+        singer: String,
+        options: AddOptions,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<AddResult> {
+        // A client retrying an add that went through gets the same result again.
+        if let Some(key) = &options.idempotency_key {
+            if let Some(result) = self.song_queue.write().await.repeated_add(key) {
+                return Ok(result);
+            }
+        }
+        if self.song_queue.read().await.adds_closed {
+            return Ok(AddResult::AddsClosed);
+        }
+        if let Some(add_guard) = &self.add_guard {
+            if !add_guard
+                .verify(song, &singer, options.token.as_deref())
+                .await
+            {
+                return Ok(AddResult::Unverified);
+            }
+        }
+        let remembered_singer = options
+            .device
+            .clone()
+            .map(|device| (device, singer.clone()));
+        let result = self.add_unguarded(song, singer, options, index).await?;
+        if let Some((device, singer)) = remembered_singer {
+            if matches!(
+                result,
+                AddResult::Added(_)
+                    | AddResult::AddedSimilar { .. }
+                    | AddResult::AddedSameName { .. }
+            ) {
+                self.song_queue
+                    .write()
+                    .await
+                    .device_singers
+                    .insert(device, (singer, OffsetDateTime::now_utc()));
+            }
+        }
+        Ok(result)
+    }
+
+    /// The singer name last added from this device, so shared devices can suggest it.
+    pub async fn last_singer(&self, device: &str) -> Option<String> {
+        let mut queue = self.song_queue.write().await;
+        let cutoff =
+            OffsetDateTime::now_utc() - Duration::seconds(self.settings.device_memory as _);
+        queue
+            .device_singers
+            .retain(|_, (_, added_at)| *added_at >= cutoff);
+        queue
+            .device_singers
+            .get(device)
+            .map(|(singer, _)| singer.clone())
+    }
+
+    /// Adds several songs at once (for admins, so the add guard doesn't apply) and reports what
+    /// happened to each of them.
+    pub async fn add_many(
+        &self,
+        songs: Vec<BulkAdd>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<BulkReport> {
+        let mut queue = self.song_queue.write().await;
+        let before = queue.list.len();
+        let mut results = Vec::with_capacity(songs.len());
+        let mut failure = None;
+        for BulkAdd { song, singer } in songs {
+            match self
+                .add_locked(&mut queue, song, singer, AddOptions::default(), index)
+                .await
+            {
+                Ok(result) => results.push(result),
+                Err(err) => {
+                    failure = Some(err.context(format!("Adding song {song} failed")));
+                    break;
+                }
+            }
+        }
+        // The songs added before a failure stay, so they're published either way.
+        if queue.list.len() != before {
+            self.did_change(&mut queue, index).await?;
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(BulkReport::new(results)),
+        }
+    }
+
+    /// Holds a place at the end of the queue for someone who picks their song later with the returned code.
+    pub async fn reserve_slot(&self) -> ReservedSlot {
+        let mut queue = self.song_queue.write().await;
+        let code = loop {
+            let mut rng = rand::thread_rng();
+            let code: String = (0..CLAIM_CODE_LENGTH)
+                .map(|_| CLAIM_CODE_CHARS[rng.gen_range(0..CLAIM_CODE_CHARS.len())] as char)
+                .collect();
+            if !queue
+                .reservations
+                .iter()
+                .any(|reservation| reservation.code == code)
+            {
+                break code;
+            }
+        };
+        let id = Uuid::new_v4();
+        let after = queue.list.back().map(|entry| entry.id);
+        queue.reservations.push(Reservation {
+            id,
+            code: code.clone(),
+            after,
+            expires_at: OffsetDateTime::now_utc()
+                + Duration::seconds(self.settings.reservation_ttl as _),
+        });
+        ReservedSlot { id, code }
+    }
+
+    /// Queues the song in the slot reserved with `code`. If the song can't be added, the slot stays reserved.
+    pub async fn claim_slot(
+        &self,
+        code: &str,
+        song: i64,
+        singer: String,
+        password: Option<String>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<AddResult> {
+        let reservation = {
+            let mut queue = self.song_queue.write().await;
+            let now = OffsetDateTime::now_utc();
+            queue
+                .reservations
+                .retain(|reservation| reservation.expires_at > now);
+            let Some(position) = queue
+                .reservations
+                .iter()
+                .position(|reservation| reservation.code.eq_ignore_ascii_case(code.trim()))
+            else {
+                return Ok(AddResult::UnknownClaimCode);
+            };
+            queue.reservations.remove(position)
+        };
+
+        let options = AddOptions {
+            password,
+            ..Default::default()
+        };
+        let result = self.add_unguarded(song, singer, options, index).await;
+        let added = match result {
+            Ok(
+                AddResult::Added(id)
+                | AddResult::AddedSimilar { id, .. }
+                | AddResult::AddedSameName { id, .. },
+            ) => id,
+            _ => {
+                self.song_queue.write().await.reservations.push(reservation);
+                return result;
+            }
+        };
+
+        let mut queue = self.song_queue.write().await;
+        if let Some(position) = Self::find_song_in_queue(&queue.list, added) {
+            Self::keep_pins_in_place(&mut queue.list, |list| {
+                let mut entry = list.remove(position).unwrap();
+                entry.id = reservation.id;
+                let target = reservation
+                    .after
+                    .and_then(|after| Self::find_song_in_queue(list, after))
+                    .map_or_else(
+                        || list.iter().take_while(|entry| entry.is_played()).count(),
+                        |after| after + 1,
+                    );
+                list.insert(target, entry);
+            });
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(match result? {
+            AddResult::AddedSimilar { similar, .. } => AddResult::AddedSimilar {
+                id: reservation.id,
+                similar,
+            },
+            AddResult::AddedSameName { other, .. } => AddResult::AddedSameName {
+                id: reservation.id,
+                other,
+            },
+            _ => AddResult::Added(reservation.id),
+        })
+    }
+
+    async fn add_unguarded(
+        &self,
+        song: i64,
+        singer: String,
+        options: AddOptions,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<AddResult> {
+        let mut queue = self.song_queue.write().await;
+        let before = queue.list.len();
+        let result = self
+            .add_locked(&mut queue, song, singer, options, index)
+            .await?;
+        if queue.list.len() != before {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(result)
+    }
+
+    /// Adds the song without publishing the change, so several adds can be published at once.
+    async fn add_locked(
+        &self,
+        queue: &mut InnerPlaylist,
+        song: i64,
+        singer: String,
+        options: AddOptions,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<AddResult> {
+        let AddOptions {
+            password,
+            priority,
+            idempotency_key,
+            performed_by,
+            singer_id,
+            ..
+        } = options;
+        let song = self.canonical(song);
+        if !self.valid_songs.read().await.contains(&song) {
+            // Anyone can add, so only ids that could belong to a song of the catalog are counted
+            // and the number of them is capped. Persisted with the next change to the playlist.
+            let in_catalog_range = song > 0 && song <= self.max_song_id.load(Ordering::Relaxed);
+            if in_catalog_range
+                && (queue.missing_requests.len() < MAX_MISSING_SONGS
+                    || queue.missing_requests.contains_key(&song))
+            {
+                *queue.missing_requests.entry(song).or_default() += 1;
+            }
+            return Ok(AddResult::UnknownSong);
+        }
+        let songs = index.songs_by_ids(vec![song]).await?;
+        if songs.is_empty() {
+            log::error!("Can't find song that we should have!");
+            Err(anyhow::anyhow!("Can't find song"))
+        } else if self.settings.family_friendly && songs[0].explicit {
+            Ok(AddResult::Explicit)
+        } else {
+            // Check again in case the same add was running concurrently.
+            if let Some(result) = idempotency_key
+                .as_deref()
+                .and_then(|key| queue.repeated_add(key))
+            {
+                return Ok(result);
+            }
+            let similar = self.find_similar(queue, &songs[0], index).await?;
+            if let Some(similar) = similar {
+                if self.settings.similar_songs == SimilarSongPolicy::Block {
+                    return Ok(AddResult::Similar(similar));
+                }
+            }
+            // Played entries kept in the list ended in the past, the new song can't start before now.
+            let last_end = match queue.list.back() {
+                Some(last) if !last.is_played() => last.predicted_end,
+                _ => queue.baseline(),
+            };
+            let predicted_end = last_end
+                .checked_add(self.song_duration(queue, &songs[0]))
+                .unwrap_or(last_end);
+            let maybe_hash = password.map(|password| self.hash_password(&password));
+            let same_name = if self.settings.warn_same_name {
+                queue
+                    .list
+                    .iter()
+                    .find(|entry| {
+                        !entry.is_played()
+                            && same_singer(&entry.requested_by, &singer)
+                            && match (&entry.singer_id, &singer_id) {
+                                (Some(id), Some(other_id)) => id != other_id,
+                                _ => entry.password_hash != maybe_hash,
+                            }
+                    })
+                    .map(|entry| entry.id)
+            } else {
+                None
+            };
+            let mut entry = PlaylistEntry::new(song, singer, maybe_hash, predicted_end);
+            entry.priority = priority;
+            entry.performed_by = performed_by;
+            entry.singer_id = singer_id;
+            let id = entry.id;
+            if let Some(max_wait) = self.settings.max_predicted_wait {
+                let mut list = queue.list.clone();
+                list.push_back(entry.clone());
+                self.place_new_entry(&mut list, &queue.play_history, index)
+                    .await?;
+                let position = Self::find_song_in_queue(&list, id).unwrap_or(list.len() - 1);
+                if self.wait_before(queue, &list, position, index).await?
+                    > Duration::seconds(max_wait as _)
+                {
+                    return Ok(AddResult::QueueTooLong);
+                }
+            }
+            queue.list.push_back(entry);
+            self.place_new_entry(&mut queue.list, &queue.play_history, index)
+                .await?;
+            let result = match (similar, same_name) {
+                (Some(similar), _) => AddResult::AddedSimilar { id, similar },
+                (None, Some(other)) => AddResult::AddedSameName { id, other },
+                (None, None) => AddResult::Added(id),
+            };
+            if let Some(key) = idempotency_key {
+                queue
+                    .recent_adds
+                    .insert(key, (OffsetDateTime::now_utc(), result));
+            }
+            Ok(result)
+        }
+    }
+
+    /// Looks for a song similar to `song` among the last waiting entries, if that is enabled.
+    /// The same song is looked for in the whole queue, wherever it is (held, pinned, ...), and in
+    /// the song currently playing.
+    async fn find_similar(
+        &self,
+        queue: &InnerPlaylist,
+        song: &Song,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<Option<Uuid>> {
+        if self.settings.similar_songs == SimilarSongPolicy::Off {
+            return Ok(None);
+        }
+        if let Some(same) = queue
+            .list
+            .iter()
+            .filter(|entry| !entry.is_played())
+            .chain(queue.now_playing())
+            .find(|entry| entry.song == song.row_id)
+        {
+            return Ok(Some(same.id));
+        }
+        let window: Vec<_> = queue
+            .list
+            .iter()
+            .filter(|entry| !entry.is_played())
+            .rev()
+            .take(self.settings.similar_songs_window)
+            .collect();
+        let songs = Self::songs_by_id(window.iter().copied(), index).await?;
+        let words = Self::artist_and_title_words(song);
+        Ok(window
+            .into_iter()
+            .find(|entry| {
+                songs.get(&entry.song).is_some_and(|other| {
+                    let other_words = Self::artist_and_title_words(other);
+                    let common = words.intersection(&other_words).count();
+                    let total = words.union(&other_words).count().max(1);
+                    common as f64 / total as f64 >= self.settings.similar_songs_threshold
+                })
+            })
+            .map(|entry| entry.id))
+    }
+
+    fn artist_and_title_words(song: &Song) -> HashSet<String> {
+        normalized_words(&format!("{} {}", song.artist, song.title))
+            .into_iter()
+            .collect()
+    }
+
+    /// Applies the insertion policies to an entry that was just appended to the list.
+    async fn place_new_entry(
+        &self,
+        playlist: &mut VecDeque<PlaylistEntry>,
+        history: &VecDeque<PlaylistEntry>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<()> {
+        let Some(id) = playlist.back().map(|entry| entry.id) else {
+            return Ok(());
+        };
+        if let Some(priority) = playlist.back().map(|entry| entry.priority) {
+            // Move ahead of everything with a lower priority, but not past entries that can't move.
+            let last = playlist.len() - 1;
+            let position = playlist
+                .iter()
+                .take(last)
+                .rposition(|entry| entry.locked().is_some() || entry.priority >= priority)
+                .map_or(0, |idx| idx + 1);
+            if position < last {
+                if let Some(entry) = playlist.pop_back() {
+                    playlist.insert(position, entry);
+                }
+            }
+        }
+        if self.settings.spread_artists {
+            let songs = Self::songs_by_id(&*playlist, index).await?;
+            Self::keep_pins_in_place(playlist, |list| {
+                Self::spread_by_artist(
+                    list,
+                    &songs,
+                    self.settings.min_songs_between_same_singer,
+                    self.settings.fairness_counts,
+                )
+            });
+        }
+        let min_gap = self.settings.min_songs_between_same_singer;
+        if min_gap > 0 {
+            let field = self.settings.fairness_counts;
+            Self::keep_pins_in_place(playlist, |list| {
+                Self::keep_gap_to_previous(list, id, min_gap, field)
+            });
+        }
+        if self.settings.singer_cooldown > 0 {
+            Self::keep_pins_in_place(playlist, |list| self.delay_for_cooldown(list, history, id));
+        }
+        Ok(())
+    }
+
+    /// Moves the entry back until at least `min_gap` other songs are between it and the singer's
+    /// previous waiting entry, or to the end if the list is too short for that.
+    fn keep_gap_to_previous(
+        playlist: &mut VecDeque<PlaylistEntry>,
+        id: Uuid,
+        min_gap: usize,
+        field: SingerField,
+    ) {
+        let Some(position) = Self::find_song_in_queue(playlist, id) else {
+            return;
+        };
+        if let Some(entry) = playlist.remove(position) {
+            let target = playlist
+                .iter()
+                .rposition(|other| !other.is_played() && other.same_singer_as(&entry, field))
+                .map_or(position, |previous| position.max(previous + min_gap + 1))
+                .min(playlist.len());
+            playlist.insert(target, entry);
+        }
+    }
+
+    /// Moves the entry back until its singer's last performance started at least `singer_cooldown`
+    /// seconds before it would start, going by the current predictions.
+    fn delay_for_cooldown(
+        &self,
+        playlist: &mut VecDeque<PlaylistEntry>,
+        history: &VecDeque<PlaylistEntry>,
+        id: Uuid,
+    ) {
+        let Some(position) = Self::find_song_in_queue(playlist, id) else {
+            return;
+        };
+        let field = self.settings.fairness_counts;
+        let Some(last_start) = history
+            .iter()
+            .chain(playlist.iter())
+            .filter(|other| other.id != id && other.same_singer_as(&playlist[position], field))
+            .filter_map(|other| other.started_at)
+            .max()
+        else {
+            return;
+        };
+        let earliest = last_start + Duration::seconds(self.settings.singer_cooldown as _);
+        let now = OffsetDateTime::now_utc();
+        let start = match position.checked_sub(1).map(|previous| &playlist[previous]) {
+            Some(previous) if !previous.is_played() => previous.predicted_end.max(now),
+            _ => now,
+        };
+        if start >= earliest {
+            return;
+        }
+        if let Some(entry) = playlist.remove(position) {
+            // Right after the first entry that ends once the cooldown is over.
+            let target = (position..playlist.len())
+                .find(|&idx| playlist[idx].predicted_end >= earliest)
+                .map_or(playlist.len(), |idx| idx + 1);
+            playlist.insert(target, entry);
+        }
+    }
+
+    /// The index in the list a song would end up at if it was added now.
+    pub async fn predict_position(
+        &self,
+        song: i64,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<usize> {
+        let queue = self.song_queue.read().await;
+        let mut list = queue.list.clone();
+        let entry = PlaylistEntry::new(
+            self.canonical(song),
+            String::new(),
+            None,
+            OffsetDateTime::now_utc(),
+        );
+        let id = entry.id;
+        list.push_back(entry);
+        self.place_new_entry(&mut list, &queue.play_history, index)
+            .await?;
+        Ok(Self::find_song_in_queue(&list, id).unwrap_or(list.len() - 1))
+    }
+
+    pub async fn play(&self, id: Uuid, index: &dyn SongCatalog) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        if let Some(entry) = queue
+            .list
+            .iter()
+            .enumerate()
+            .find_map(|(idx, entry)| (entry.id == id && !entry.is_played()).then_some(idx))
+        {
+            self.play_at(&mut queue, entry, index).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Plays the entry at `entry` in the list and returns it as it's playing now, unless its song
+    /// couldn't be looked up.
+    async fn play_at(
+        &self,
+        queue: &mut InnerPlaylist,
+        entry: usize,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<Option<PlaylistEntry>> {
+        match index.songs_by_ids(vec![queue.list[entry].song]).await {
+            Err(err) => {
+                log::error!("Fetching song for song log failed: {err:?}");
+                Ok(None)
+            }
+            Ok(songs) => {
+                // Write song log
+                if self.rated_song_log() {
+                    // The previous performance wasn't rated, so its row is written without a rating.
+                    self.flush_unrated(queue, index).await?;
+                    queue.unrated = Some(queue.list[entry].id);
+                } else if let Some(song_log) = &self.song_log {
+                    let written = if let Some(song) = songs.first() {
+                        let record = self.song_log_record(queue, &queue.list[entry], song, None);
+                        let result = song_log.write_record(record).await;
+                        let written = result.is_ok();
+                        self.track_log_write("song log", result);
+                        written
+                    } else {
+                        log::error!("Can't write song log: song not found!");
+                        false
+                    };
+                    if !written && self.settings.song_log_failures == LogFailurePolicy::Strict {
+                        // Nothing has changed yet, so the entry simply stays in the queue.
+                        return Err(anyhow::anyhow!(
+                            "Not playing {}, the song log couldn't be written",
+                            queue.list[entry].id
+                        ));
+                    }
+                }
+
+                let old_playing_end = queue.now_playing().map(|entry| entry.predicted_end);
+                let old_playing_start = queue
+                    .now_playing()
+                    .and_then(|entry| Some((entry.song, entry.started_at?)));
+                let mut playing = None;
+
+                if let Some(mut new_playing) = queue.list.remove(entry) {
+                    let now = OffsetDateTime::now_utc();
+                    new_playing.started_at = Some(now);
+
+                    // How long the previous song actually took, without the usual break after it
+                    if let Some((song, started_at)) = old_playing_start {
+                        let elapsed = now - started_at;
+                        if elapsed.is_positive()
+                            && elapsed < Duration::seconds_f64(MAX_SONG_DURATION) + MAX_INTERMISSION
+                        {
+                            let actual =
+                                (elapsed - queue.average_intermission()).max(Duration::ZERO);
+                            if let Some(record) = queue.play_record.get_mut(&self.canonical(song)) {
+                                record.actual_total += actual.as_seconds_f64();
+                                record.actual_count += 1;
+                            }
+                        }
+                    }
+
+                    queue
+                        .play_record
+                        .entry(self.canonical(new_playing.song))
+                        .and_modify(|record| {
+                            record.count += 1;
+                            record.last_played = now;
+                        })
+                        .or_insert(PlayRecord {
+                            count: 1,
+                            last_played: now,
+                            actual_total: 0.0,
+                            actual_count: 0,
+                        });
+
+                    // Update intermission record
+                    if let Some(old_playing_end) = old_playing_end {
+                        let duration = now - old_playing_end;
+                        if duration < MAX_INTERMISSION && duration.is_positive() {
+                            queue.intermission_count += 1;
+                            queue.intermission_duration += duration;
+                            new_playing.intermission = Some(duration.as_seconds_f64());
+                        }
+                    }
+
+                    playing = Some(new_playing.clone());
+                    if self.settings.keep_played_in_list {
+                        new_playing.status = EntryStatus::Played;
+                        let played = queue
+                            .list
+                            .iter()
+                            .take_while(|entry| entry.is_played())
+                            .count();
+                        queue.list.insert(played, new_playing);
+                    } else {
+                        queue.play_history.push_back(new_playing);
+                    }
+                }
+
+                if self.settings.auto_promote_every > 0 {
+                    queue.plays_since_promotion += 1;
+                    if queue.plays_since_promotion >= self.settings.auto_promote_every {
+                        queue.plays_since_promotion = 0;
+                        if let Some(distance) = Self::promote_longest_waiting_in(
+                            &mut queue.list,
+                            self.settings.min_songs_between_same_singer,
+                            self.settings.fairness_counts,
+                        ) {
+                            queue.churn.record(|churn| &mut churn.promotions, distance);
+                        }
+                    }
+                }
+
+                // Only keep the configured amount of live history around
+                while queue.play_history.len() > self.settings.max_play_history {
+                    queue.play_history.pop_front();
+                }
+
+                // Update playlist and notify listeners
+                self.did_change(queue, index).await?;
+                Ok(playing)
+            }
+        }
+    }
+
+    /// Adds the breaks between historical plays to the intermission statistics, so predictions are
+    /// accurate from the first song on. Returns how many breaks were counted.
+    pub async fn seed_intermissions(
+        &self,
+        mut plays: Vec<HistoricalPlay>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<usize> {
+        plays.sort_by_key(|play| play.start);
+        let breaks: Vec<_> = plays
+            .windows(2)
+            .filter(|pair| (0.0..=MAX_SONG_DURATION).contains(&pair[0].duration))
+            .map(|pair| pair[1].start - pair[0].start - Duration::seconds_f64(pair[0].duration))
+            .filter(|duration| *duration < MAX_INTERMISSION && duration.is_positive())
+            .collect();
+        if breaks.is_empty() {
+            return Ok(0);
+        }
+        let mut queue = self.song_queue.write().await;
+        queue.intermission_count += breaks.len();
+        for duration in &breaks {
+            queue.intermission_duration += *duration;
+        }
+        self.did_change(&mut queue, index).await?;
+        Ok(breaks.len())
+    }
+
+    /// Puts an entry that was played by accident back at the front of the queue. Its play and the
+    /// break before it no longer count towards the statistics.
+    pub async fn unplay(&self, id: Uuid, index: &dyn SongCatalog) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        let entry = if let Some(idx) = queue.play_history.iter().position(|entry| entry.id == id) {
+            queue.play_history.remove(idx)
+        } else if let Some(idx) = queue
+            .list
+            .iter()
+            .position(|entry| entry.id == id && entry.is_played())
+        {
+            queue.list.remove(idx)
+        } else {
+            None
+        };
+        let Some(mut entry) = entry else {
+            return Ok(EditResult::Unchanged);
+        };
+
+        let song = self.canonical(entry.song);
+        let never_played = queue.play_record.get_mut(&song).is_some_and(|record| {
+            record.count = record.count.saturating_sub(1);
+            record.count == 0
+        });
+        if never_played {
+            queue.play_record.remove(&song);
+        }
+        if let Some(intermission) = entry.intermission.take() {
+            queue.intermission_count = queue.intermission_count.saturating_sub(1);
+            queue.intermission_duration -= Duration::seconds_f64(intermission);
+        }
+
+        entry.started_at = None;
+        entry.status = EntryStatus::Queued;
+        let front = queue
+            .list
+            .iter()
+            .take_while(|entry| entry.is_played())
+            .count();
+        queue.list.insert(front, entry);
+        self.did_change(&mut queue, index).await?;
+        Ok(EditResult::Changed)
+    }
+
+    /// Plays whatever is at the front of the queue.
+    /// The front is looked up and played under the same lock, so concurrent calls (e.g. from autoplay
+    /// and an admin) never play the same entry twice and nothing can be moved in between.
+    pub async fn play_front(&self, index: &dyn SongCatalog) -> anyhow::Result<PlayResult> {
+        let mut queue = self.song_queue.write().await;
+        let Some(front) = queue.list.iter().position(|entry| !entry.is_played()) else {
+            return Ok(PlayResult::QueueEmpty);
+        };
+        match self.play_at(&mut queue, front, index).await? {
+            Some(playing) => Ok(PlayResult::Played(Box::new(playing))),
+            None => Err(anyhow::anyhow!("Couldn't play the front of the queue")),
+        }
+    }
+
+    /// The id a song is known as, which differs for duplicates in the catalog.
+    fn canonical(&self, song: i64) -> i64 {
+        self.settings
+            .song_aliases
+            .get(&song)
+            .copied()
+            .unwrap_or(song)
+    }
+
+    fn find_song_in_queue(playlist: &VecDeque<PlaylistEntry>, id: Uuid) -> Option<usize> {
+        playlist
+            .iter()
+            .enumerate()
+            .find_map(|(idx, entry)| (entry.id == id).then_some(idx))
+    }
+
+    /// Runs a reordering of the list and afterwards puts all pinned (and played) entries back at the
+    /// index they had before. The reordering itself must not change the order of those entries.
+    fn keep_pins_in_place<R>(
+        playlist: &mut VecDeque<PlaylistEntry>,
+        reorder: impl FnOnce(&mut VecDeque<PlaylistEntry>) -> R,
+    ) -> R {
+        let pins: Vec<_> = playlist
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, entry)| entry.locked().is_some().then_some(idx))
+            .collect();
+        let result = reorder(playlist);
+        if !pins.is_empty() {
+            let (pinned, mut unpinned): (VecDeque<_>, VecDeque<_>) = playlist
+                .drain(..)
+                .partition(|entry| entry.locked().is_some());
+            for (idx, entry) in pins.into_iter().zip(pinned) {
+                unpinned.insert(idx.min(unpinned.len()), entry);
+            }
+            *playlist = unpinned;
+        }
+        result
+    }
+
+    pub async fn set_pinned(
+        &self,
+        id: Uuid,
+        pinned: bool,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
+            if queue.list[queue_index].pinned != pinned {
+                queue.list[queue_index].pinned = pinned;
+                self.did_change(&mut queue, index).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Sets the label shown on the screen instead of the song and singer, or removes it. Control
+    /// characters are dropped and long labels are cut off.
+    pub async fn set_label(
+        &self,
+        id: Uuid,
+        label: Option<String>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let label = label
+            .map(|label| {
+                label
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .take(MAX_LABEL_LENGTH)
+                    .collect::<String>()
+                    .trim()
+                    .to_owned()
+            })
+            .filter(|label| !label.is_empty());
+        let mut queue = self.song_queue.write().await;
+        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
+            if queue.list[queue_index].display_label != label {
+                queue.list[queue_index].display_label = label;
+                self.did_change(&mut queue, index).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Keeps the entry where it is while its payment is confirmed, until [`Self::release_hold`] or the
+    /// hold times out.
+    pub async fn hold_position(
+        &self,
+        id: Uuid,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        let Some(entry) = Self::find_song_in_queue(&queue.list, id) else {
+            return Ok(EditResult::Unchanged);
+        };
+        if let Some(result) = queue.list[entry].locked() {
+            return Ok(result);
+        }
+        queue.list[entry].held_until =
+            Some(OffsetDateTime::now_utc() + Duration::seconds(self.settings.hold_timeout as _));
+        self.did_change(&mut queue, index).await?;
+        Ok(EditResult::Changed)
+    }
+
+    /// Ends the hold on the entry once its payment is confirmed, optionally giving it a priority so
+    /// later adds can't get ahead of it.
+    pub async fn release_hold(
+        &self,
+        id: Uuid,
+        priority: Option<u8>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        let Some(entry) = Self::find_song_in_queue(&queue.list, id) else {
+            return Ok(EditResult::Unchanged);
+        };
+        let entry = &mut queue.list[entry];
+        if entry.held_until.take().is_none() {
+            return Ok(EditResult::Unchanged);
+        }
+        if let Some(priority) = priority {
+            entry.priority = priority;
+        }
+        self.did_change(&mut queue, index).await?;
+        Ok(EditResult::Changed)
+    }
+
+    /// Sets who sings the song, or that the one who requested it does.
+    pub async fn set_performer(
+        &self,
+        id: Uuid,
+        performed_by: Option<String>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
+            if queue.list[queue_index].performed_by != performed_by {
+                queue.list[queue_index].performed_by = performed_by;
+                self.did_change(&mut queue, index).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Closes the queue for new songs (last call) or opens it again. Everything already queued can
+    /// still be played and reordered.
+    pub async fn set_accepting_adds(
+        &self,
+        accepting: bool,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<()> {
+        let mut queue = self.song_queue.write().await;
+        if queue.adds_closed == accepting {
+            queue.adds_closed = !accepting;
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the performance that is playing with a rating (or none) for the song log.
+    /// Returns false if the entry isn't waiting for one, e.g. because the next song already started.
+    pub async fn rate(
+        &self,
+        id: Uuid,
+        rating: Option<f64>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        if !self.rated_song_log() || queue.unrated != Some(id) {
+            return Ok(false);
+        }
+        queue.unrated = None;
+        let rating = rating.filter(|rating| rating.is_finite());
+        self.finish_song_log(&queue, id, rating, index).await?;
+        self.did_change(&mut queue, index).await?;
+        Ok(true)
+    }
+
+    /// Starts a new session, from now on the logs are tagged with it. `None` ends the current one.
+    pub async fn start_session(
+        &self,
+        session: Option<String>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<()> {
+        let mut queue = self.song_queue.write().await;
+        let session = session
+            .map(|session| session.trim().to_owned())
+            .filter(|session| !session.is_empty());
+        if queue.session != session {
+            log::info!("Session {:?} ended, starting {session:?}", queue.session);
+            // The last performance of the old session belongs to it in the song log.
+            self.flush_unrated(&mut queue, index).await?;
+            queue.session = session;
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(())
+    }
+
+    /// Schedules the entry to move to the front of the queue at the given time, or unschedules it.
+    pub async fn schedule(
+        &self,
+        id: Uuid,
+        at: Option<OffsetDateTime>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
+            let entry = &mut queue.list[queue_index];
+            if entry.is_played() {
+                return Ok(EditResult::Unchanged);
+            }
+            if entry.scheduled_for != at {
+                entry.scheduled_for = at;
+                self.did_change(&mut queue, index).await?;
+                return Ok(EditResult::Changed);
+            }
+        }
+        Ok(EditResult::Unchanged)
+    }
+
+    /// Moves the entries whose time has come to the front of the queue, earliest first.
+    /// Afterwards they're no longer scheduled, so they are only promoted once.
+    fn promote_scheduled(playlist: &mut VecDeque<PlaylistEntry>, now: OffsetDateTime) -> bool {
+        let is_due = |entry: &PlaylistEntry| {
+            entry.locked().is_none() && entry.scheduled_for.is_some_and(|at| at <= now)
+        };
+        if !playlist.iter().any(is_due) {
+            return false;
+        }
+        Self::keep_pins_in_place(playlist, |list| {
+            let mut due = Vec::new();
+            let mut idx = 0;
+            while idx < list.len() {
+                if is_due(&list[idx]) {
+                    due.extend(list.remove(idx));
+                } else {
+                    idx += 1;
+                }
+            }
+            due.sort_by_key(|entry| entry.scheduled_for);
+            let front = list.iter().take_while(|entry| entry.is_played()).count();
+            for (offset, mut entry) in due.into_iter().enumerate() {
+                entry.scheduled_for = None;
+                list.insert(front + offset, entry);
+            }
+        });
+        true
+    }
+
+    pub async fn remove(&self, id: Uuid, index: &dyn SongCatalog) -> anyhow::Result<EditResult> {
+        self.edit_list(|queue| queue.remove(id), index).await
+    }
+
+    pub async fn remove_if_password_correct(
+        &self,
+        id: Uuid,
+        password: String,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        let now = OffsetDateTime::now_utc();
+        let window_start = now - Duration::seconds(self.settings.password_lockout as _);
+        queue
+            .failed_attempts
+            .retain(|_, (_, first_failure)| *first_failure >= window_start);
+        if let Some(queue_index) = Self::find_song_in_queue(&queue.list, id) {
+            if let Some(hash) = &queue.list[queue_index].password_hash {
+                let failures = queue
+                    .failed_attempts
+                    .get(&id)
+                    .map_or(0, |(failures, _)| *failures);
+                if self.settings.password_attempts > 0
+                    && failures >= self.settings.password_attempts
+                {
+                    return Ok(EditResult::Locked);
+                }
+                if &self.hash_password(&password) == hash {
+                    queue.failed_attempts.remove(&id);
+                    if let Some(locked) = queue.list[queue_index].locked() {
+                        return Ok(locked);
+                    }
+                    queue.list.remove(queue_index);
+                    self.did_change(&mut queue, index).await?;
+                    return Ok(EditResult::Changed);
+                }
+                queue.failed_attempts.entry(id).or_insert((0, now)).0 += 1;
+            }
+        }
+        Ok(EditResult::Unchanged)
+    }
+
+    /// Removes the passwords of all queued entries, e.g. when the host takes over from a device that
+    /// died. Returns how many entries had one.
+    pub async fn clear_passwords(&self, index: &dyn SongCatalog) -> anyhow::Result<usize> {
+        let mut queue = self.song_queue.write().await;
+        let cleared = queue
+            .list
+            .iter_mut()
+            .filter_map(|entry| entry.password_hash.take())
+            .count();
+        queue.failed_attempts.clear();
+        if cleared > 0 {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(cleared)
+    }
+
+    pub async fn swap(
+        &self,
+        id1: Uuid,
+        id2: Uuid,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        self.edit_list(|queue| queue.swap(id1, id2), index).await
+    }
+
+    /// Like [`Playlist::swap`], for clients that only know where the entries are in the list.
+    pub async fn swap_positions(
+        &self,
+        i: usize,
+        j: usize,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        if i == j || i >= queue.list.len() || j >= queue.list.len() {
+            return Ok(EditResult::Unchanged);
+        }
+        if let Some(result) = queue.list[i].locked().or_else(|| queue.list[j].locked()) {
+            return Ok(result);
+        }
+        queue.list.swap(i, j);
+        queue.churn.record(|churn| &mut churn.swaps, i.abs_diff(j));
+        self.did_change(&mut queue, index).await?;
+        Ok(EditResult::Changed)
+    }
+
+    pub async fn move_after(
+        &self,
+        id: Uuid,
+        after: Uuid,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        self.edit_list(|queue| queue.move_after(id, after), index)
+            .await
+    }
+
+    pub async fn move_top(&self, id: Uuid, index: &dyn SongCatalog) -> anyhow::Result<EditResult> {
+        self.edit_list(|queue| queue.move_top(id), index).await
+    }
+
+    /// Applies an edit to the list and, if it changed anything, persists and broadcasts it.
+    async fn edit_list(
+        &self,
+        edit: impl FnOnce(&mut InnerPlaylist) -> EditResult,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        let result = edit(&mut queue);
+        if result == EditResult::Changed {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(result)
+    }
+
+    /// Applies all operations at once, with a single broadcast. Unless every operation changes
+    /// something, none of them are applied.
+    pub async fn batch(
+        &self,
+        ops: Vec<QueueOp>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<BatchResult> {
+        let mut queue = self.song_queue.write().await;
+        let list = queue.list.clone();
+        let churn = queue.churn.clone();
+        let mut results = Vec::new();
+        for op in ops {
+            let result = match op {
+                QueueOp::MoveTop { id } => queue.move_top(id),
+                QueueOp::MoveAfter { id, after } => queue.move_after(id, after),
+                QueueOp::Swap { id1, id2 } => queue.swap(id1, id2),
+                QueueOp::Remove { id } => queue.remove(id),
+            };
+            results.push(result);
+            if result != EditResult::Changed {
+                queue.list = list;
+                queue.churn = churn;
+                return Ok(BatchResult {
+                    applied: false,
+                    results,
+                });
+            }
+        }
+        if !results.is_empty() {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(BatchResult {
+            applied: true,
+            results,
+        })
+    }
+
+    /// Queues the most played song of the session again at the front of the queue.
+    /// Returns the id of the new entry, or nothing if no song was played yet.
+    pub async fn encore(&self, index: &dyn SongCatalog) -> anyhow::Result<Option<Uuid>> {
+        let mut queue = self.song_queue.write().await;
+        let Some(song) = queue
+            .play_record
+            .iter()
+            .max_by_key(|(_, record)| (record.count, record.last_played))
+            .map(|(song, _)| *song)
+        else {
+            return Ok(None);
+        };
+        if !self.settings.encore_ignores_similar
+            && self.settings.similar_songs == SimilarSongPolicy::Block
         {
-            let entry = queue.list.remove(entry).unwrap();
-            queue.list.push_front(entry);
-            Self::did_change(&mut queue, &self.persist_path, index).await?;
+            let Some(resolved) = index.songs_by_ids(vec![song]).await?.pop() else {
+                return Ok(None);
+            };
+            if self.find_similar(&queue, &resolved, index).await?.is_some() {
+                return Ok(None);
+            }
+        }
+
+        let entry = PlaylistEntry::new(
+            song,
+            self.settings.encore_singer.clone(),
+            None,
+            OffsetDateTime::now_utc(),
+        );
+        let id = entry.id;
+        Self::keep_pins_in_place(&mut queue.list, |list| list.push_front(entry));
+        self.did_change(&mut queue, index).await?;
+        Ok(Some(id))
+    }
+
+    /// Moves the entry that was added the longest time ago to the front of the queue.
+    pub async fn promote_longest_waiting(&self, index: &dyn SongCatalog) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        if let Some(distance) = Self::promote_longest_waiting_in(
+            &mut queue.list,
+            self.settings.min_songs_between_same_singer,
+            self.settings.fairness_counts,
+        ) {
+            queue.churn.record(|churn| &mut churn.promotions, distance);
+            self.did_change(&mut queue, index).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Returns how many places the entry moved, or nothing if the longest waiting entry is up next
+    /// already or moving it would put the singer too close to their other songs.
+    fn promote_longest_waiting_in(
+        playlist: &mut VecDeque<PlaylistEntry>,
+        min_gap: usize,
+        field: SingerField,
+    ) -> Option<usize> {
+        let from = playlist
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.locked().is_none())
+            .min_by_key(|(_, entry)| entry.added_at)
+            .map(|(idx, _)| idx)?;
+        let to = playlist
+            .iter()
+            .position(|entry| entry.locked().is_none())
+            .unwrap_or(from);
+        if from == to || !Self::keeps_singer_gap(playlist, from, to, min_gap, field) {
+            return None;
+        }
+        Self::keep_pins_in_place(playlist, |list| {
+            if let Some(entry) = list.remove(from) {
+                list.insert(to, entry);
+            }
+        });
+        Some(from - to)
+    }
+
+    /// Swaps the entry with the closest movable entry in the given direction.
+    pub async fn nudge(
+        &self,
+        id: Uuid,
+        direction: Direction,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<EditResult> {
+        let mut queue = self.song_queue.write().await;
+        if let Some(entry) = Self::find_song_in_queue(&queue.list, id) {
+            if let Some(result) = queue.list[entry].locked() {
+                return Ok(result);
+            }
+            let neighbor = match direction {
+                Direction::Up => (0..entry)
+                    .rev()
+                    .find(|&idx| queue.list[idx].locked().is_none()),
+                Direction::Down => {
+                    (entry + 1..queue.list.len()).find(|&idx| queue.list[idx].locked().is_none())
+                }
+            };
+            if let Some(neighbor) = neighbor {
+                queue.list.swap(entry, neighbor);
+                queue
+                    .churn
+                    .record(|churn| &mut churn.moves, entry.abs_diff(neighbor));
+                self.did_change(&mut queue, index).await?;
+                return Ok(EditResult::Changed);
+            }
+        }
+        Ok(EditResult::Unchanged)
+    }
+
+    /// Whether moving the entry at `from` forward to `to` still leaves at least `min_gap` other
+    /// entries between it and any other entry of the same singer.
+    fn keeps_singer_gap(
+        playlist: &VecDeque<PlaylistEntry>,
+        from: usize,
+        to: usize,
+        min_gap: usize,
+        field: SingerField,
+    ) -> bool {
+        let singer = &playlist[from];
+        let before = to.saturating_sub(min_gap)..to;
+        let after = to..(to + min_gap).min(from);
+        !before
+            .chain(after)
+            .any(|idx| playlist[idx].same_singer_as(singer, field))
+    }
+
+    /// The queued entries whose song can't be found in the catalog anymore, e.g. after it was
+    /// reloaded. With `remove`, those that aren't pinned are removed from the queue.
+    pub async fn find_orphans(
+        &self,
+        remove: bool,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<Vec<Uuid>> {
+        let mut queue = self.song_queue.write().await;
+        let pending = queue.list.iter().filter(|entry| !entry.is_played());
+        let songs = Self::songs_by_id(pending.clone(), index).await?;
+        let orphans: Vec<_> = pending
+            .filter(|entry| !songs.contains_key(&entry.song))
+            .map(|entry| entry.id)
+            .collect();
+        if remove && !orphans.is_empty() {
+            let before = queue.list.len();
+            queue
+                .list
+                .retain(|entry| entry.locked().is_some() || !orphans.contains(&entry.id));
+            if queue.list.len() != before {
+                self.did_change(&mut queue, index).await?;
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Removes queued entries that were added longer than `older_than` ago. Pinned entries are kept.
+    pub async fn prune_stale(
+        &self,
+        older_than: Duration,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<usize> {
+        let mut queue = self.song_queue.write().await;
+        let cutoff = OffsetDateTime::now_utc() - older_than;
+        let before = queue.list.len();
+        queue
+            .list
+            .retain(|entry| entry.locked().is_some() || entry.added_at >= cutoff);
+        let pruned = before - queue.list.len();
+        if pruned > 0 {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(pruned)
+    }
+
+    /// Removes all queued entries of the singer (except pinned ones). With a singer id, entries
+    /// that have one are matched by it. They can be restored with [`Self::restore_singer`] during
+    /// the configured grace period.
+    pub async fn remove_singer(
+        &self,
+        singer: &str,
+        singer_id: Option<&str>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<usize> {
+        let mut queue = self.song_queue.write().await;
+        let now = OffsetDateTime::now_utc();
+        self.empty_trash(&mut queue.trash, now);
+
+        let mut removed = Vec::new();
+        let mut position = 0;
+        queue.list.retain(|entry| {
+            let keep = entry.locked().is_some() || !entry.requested_by_singer(singer, singer_id);
+            if !keep {
+                removed.push(TrashedEntry {
+                    entry: entry.clone(),
+                    position,
+                    removed_at: now,
+                });
+            }
+            position += 1;
+            keep
+        });
+        let count = removed.len();
+        if count > 0 {
+            queue.trash.extend(removed);
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(count)
+    }
+
+    /// Puts the entries removed by [`Self::remove_singer`] back where they were, if the grace period
+    /// hasn't run out yet.
+    pub async fn restore_singer(
+        &self,
+        singer: &str,
+        singer_id: Option<&str>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<usize> {
+        let mut queue = self.song_queue.write().await;
+        self.empty_trash(&mut queue.trash, OffsetDateTime::now_utc());
+
+        let (mut restored, kept): (Vec<_>, Vec<_>) = queue
+            .trash
+            .drain(..)
+            .partition(|trashed| trashed.entry.requested_by_singer(singer, singer_id));
+        queue.trash = kept;
+        // Inserting in the original order puts every entry back at its old position, unless the list
+        // got shorter in the meantime.
+        restored.sort_by_key(|trashed| trashed.position);
+        let count = restored.len();
+        for trashed in restored {
+            let position = trashed.position.min(queue.list.len());
+            queue.list.insert(position, trashed.entry);
+        }
+        if count > 0 {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(count)
+    }
+
+    fn empty_trash(&self, trash: &mut Vec<TrashedEntry>, now: OffsetDateTime) {
+        let cutoff = now - Duration::seconds(self.settings.singer_restore_grace as _);
+        trash.retain(|trashed| trashed.removed_at >= cutoff);
+    }
+
+    /// Removes all played entries from the list except the one currently playing
+    /// (only relevant when they're kept in the list).
+    pub async fn purge_played(&self, index: &dyn SongCatalog) -> anyhow::Result<usize> {
+        let mut queue = self.song_queue.write().await;
+        let before = queue.list.len();
+        let now_playing = queue.now_playing().map(|entry| entry.id);
+        queue
+            .list
+            .retain(|entry| !entry.is_played() || Some(entry.id) == now_playing);
+        let purged = before - queue.list.len();
+        if purged > 0 {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(purged)
+    }
+
+    /// Reorders the queue so that no two songs of the same artist are next to each other, where possible.
+    pub async fn spread_artists(&self, index: &dyn SongCatalog) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        let songs = Self::songs_by_id(&queue.list, index).await?;
+        if Self::keep_pins_in_place(&mut queue.list, |list| {
+            Self::spread_by_artist(
+                list,
+                &songs,
+                self.settings.min_songs_between_same_singer,
+                self.settings.fairness_counts,
+            )
+        }) {
+            self.did_change(&mut queue, index).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn songs_by_id<'a>(
+        entries: impl IntoIterator<Item = &'a PlaylistEntry>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<HashMap<i64, Song>> {
+        let ids: HashSet<_> = entries.into_iter().map(|entry| entry.song).collect();
+        let songs = index.songs_by_ids(ids.into_iter().collect()).await?;
+        Ok(songs.into_iter().map(|song| (song.row_id, song)).collect())
+    }
+
+    /// Best-effort: whenever an entry has the same artist as its predecessor, the next movable entry
+    /// with a different artist is pulled forward. Returns whether anything was moved.
+    fn spread_by_artist(
+        playlist: &mut VecDeque<PlaylistEntry>,
+        songs: &HashMap<i64, Song>,
+        min_singer_gap: usize,
+        field: SingerField,
+    ) -> bool {
+        let artist = |entry: &PlaylistEntry| songs.get(&entry.song).map(|song| &song.artist);
+        let mut changed = false;
+        for idx in 1..playlist.len() {
+            let previous = artist(&playlist[idx - 1]);
+            if artist(&playlist[idx]) != previous {
+                continue;
+            }
+            if let Some(replacement) = (idx + 1..playlist.len()).find(|&other| {
+                playlist[other].locked().is_none()
+                    && artist(&playlist[other]) != previous
+                    && Self::keeps_singer_gap(playlist, other, idx, min_singer_gap, field)
+            }) {
+                let entry = playlist.remove(replacement).unwrap();
+                playlist.insert(idx, entry);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Puts the queued entries in a random order, the same one every time for the same `seed`. Pinned
+    /// entries stay where they are. Returns whether anything was moved.
+    pub async fn shuffle(
+        &self,
+        seed: Option<u64>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let mut queue = self.song_queue.write().await;
+        let places: Vec<_> = (0..queue.list.len())
+            .filter(|&idx| queue.list[idx].locked().is_none())
+            .collect();
+        let mut entries: Vec<_> = places.iter().map(|&idx| queue.list[idx].clone()).collect();
+        entries.shuffle(&mut rng);
+        let mut changed = false;
+        for (&place, entry) in places.iter().zip(entries) {
+            changed |= queue.list[place].id != entry.id;
+            queue.list[place] = entry;
+        }
+        if changed {
+            self.did_change(&mut queue, index).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Arranges the queued songs along `curve` by their tempo. Pinned entries and songs without a known
+    /// tempo stay where they are. Returns whether anything was moved.
+    pub async fn reorder_by_energy(
+        &self,
+        curve: EnergyCurve,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let mut queue = self.song_queue.write().await;
+        let songs = Self::songs_by_id(&queue.list, index).await?;
+        if Self::arrange_by_energy(
+            &mut queue.list,
+            &songs,
+            curve,
+            self.settings.min_songs_between_same_singer,
+            self.settings.fairness_counts,
+        ) {
+            self.did_change(&mut queue, index).await?;
             Ok(true)
         } else {
-            Ok(false)
+            Ok(false)
+        }
+    }
+
+    /// Best-effort: fills the places of the movable entries with a known tempo one by one, taking the
+    /// slowest or fastest remaining song as `curve` wants, unless that singer was on within the last
+    /// `min_singer_gap` entries. Returns whether anything was moved.
+    fn arrange_by_energy(
+        playlist: &mut VecDeque<PlaylistEntry>,
+        songs: &HashMap<i64, Song>,
+        curve: EnergyCurve,
+        min_singer_gap: usize,
+        field: SingerField,
+    ) -> bool {
+        let tempo = |entry: &PlaylistEntry| songs.get(&entry.song).and_then(|song| song.tempo);
+        let places: Vec<_> = (0..playlist.len())
+            .filter(|&idx| playlist[idx].locked().is_none() && tempo(&playlist[idx]).is_some())
+            .collect();
+        let mut remaining: Vec<_> = places
+            .iter()
+            .filter_map(|&idx| Some((tempo(&playlist[idx])?, playlist[idx].clone())))
+            .collect();
+        // Stable, so songs with the same tempo keep their order.
+        remaining.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut changed = false;
+        for (position, &place) in places.iter().enumerate() {
+            let mut candidates: Vec<_> = (0..remaining.len()).collect();
+            if curve.wants_fast(position) {
+                candidates.reverse();
+            }
+            let pick = candidates
+                .iter()
+                .copied()
+                .find(|&candidate| {
+                    let singer = &remaining[candidate].1;
+                    !(place.saturating_sub(min_singer_gap)..place)
+                        .any(|idx| playlist[idx].same_singer_as(singer, field))
+                })
+                .unwrap_or(candidates[0]);
+            let (_, entry) = remaining.remove(pick);
+            changed |= playlist[place].id != entry.id;
+            playlist[place] = entry;
+        }
+        changed
+    }
+
+    /// Songs from the catalog that weren't played this session yet.
+    pub async fn unplayed_songs(
+        &self,
+        index: &dyn SongCatalog,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Song>> {
+        let queue = self.song_queue.read().await;
+        let candidates: Vec<_> = self
+            .valid_songs
+            .read()
+            .await
+            .iter()
+            .filter(|&&song| {
+                !self.settings.song_aliases.contains_key(&song)
+                    && !queue.play_record.contains_key(&song)
+            })
+            .take(limit)
+            .copied()
+            .collect();
+        let mut songs = index.songs_by_ids(candidates).await?;
+        if self.settings.family_friendly {
+            songs.retain(|song| !song.explicit);
+        }
+        Ok(songs)
+    }
+
+    /// How long it would take until a song inserted at `position` comes up, counted from now.
+    pub async fn wait_at_position(
+        &self,
+        position: usize,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<Duration> {
+        let queue = self.song_queue.read().await;
+        self.wait_before(&queue, &queue.list, position, index).await
+    }
+
+    /// The predicted wait for the entry at `position` in `list`, which is (a modified copy of) the queue's list.
+    async fn wait_before(
+        &self,
+        queue: &InnerPlaylist,
+        list: &VecDeque<PlaylistEntry>,
+        position: usize,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<Duration> {
+        let position = position.min(list.len());
+        let songs = Self::songs_by_id(list.range(..position), index).await?;
+        let average_intermission = queue.average_intermission();
+
+        let mut start = queue.baseline();
+        for entry in list.range(..position).filter(|entry| !entry.is_played()) {
+            if let Some(song) = songs.get(&entry.song) {
+                start = start
+                    .checked_add(average_intermission + self.song_duration(queue, song))
+                    .unwrap_or(start);
+            }
+        }
+        start += average_intermission;
+
+        Ok((start - OffsetDateTime::now_utc()).max(Duration::ZERO))
+    }
+
+    pub async fn report_bug(
+        &self,
+        song: i64,
+        report: &str,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<()> {
+        if !self.valid_songs.read().await.contains(&song) {
+            log::error!("Bug report for song that doesn't exist!");
+            return Ok(());
+        }
+        // Persisted with the next change to the playlist.
+        let session = {
+            let mut queue = self.song_queue.write().await;
+            *queue.bug_reports.entry(self.canonical(song)).or_default() += 1;
+            queue.session.clone()
+        };
+        let songs = index.songs_by_ids(vec![song]).await?;
+
+        if songs.is_empty() {
+            log::error!("Can't write bug log: song not found!");
+        } else {
+            let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
+            let mut record =
+                StringRecord::from(vec![&timestamp, &songs[0].artist, &songs[0].title, report]);
+            if let Some(session) = &session {
+                record.push_field(session);
+            }
+            self.track_log_write("bug log", self.bug_log.write_record(record).await);
+        }
+        Ok(())
+    }
+
+    /// The `n` songs with the most bug reports and their report count, most reported first.
+    pub async fn top_buggy_songs(&self, n: usize) -> Vec<(i64, usize)> {
+        let mut songs: Vec<_> = self
+            .song_queue
+            .read()
+            .await
+            .bug_reports
+            .iter()
+            .map(|(song, count)| (*song, *count))
+            .collect();
+        songs.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        songs.truncate(n);
+        songs
+    }
+
+    /// The songs that were requested although they aren't in the catalog with how often, most
+    /// requested first.
+    pub async fn missing_song_requests(&self) -> Vec<(i64, usize)> {
+        let mut songs: Vec<_> = self
+            .song_queue
+            .read()
+            .await
+            .missing_requests
+            .iter()
+            .map(|(song, count)| (*song, *count))
+            .collect();
+        songs.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        songs
+    }
+
+    /// Writes a summary of the session to a new file in `dir` and returns its path.
+    pub async fn archive_session(
+        &self,
+        dir: impl AsRef<Path>,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<PathBuf> {
+        let mut queue = self.song_queue.write().await;
+        if self.flush_unrated(&mut queue, index).await? {
+            self.did_change(&mut queue, index).await?;
+        }
+        let mut played: Vec<_> = queue
+            .play_history
+            .iter()
+            .chain(queue.list.iter().filter(|entry| entry.is_played()))
+            .collect();
+        played.sort_by_key(|entry| entry.started_at);
+        let songs = Self::songs_by_id(played.iter().copied(), index).await?;
+        let archived_at = OffsetDateTime::now_utc();
+        let archive = SessionArchive {
+            archived_at,
+            history: played
+                .into_iter()
+                .map(|entry| {
+                    let song = songs.get(&entry.song);
+                    ArchivedPlay {
+                        singer: entry.performer().to_owned(),
+                        started_at: entry.started_at,
+                        song: entry.song,
+                        artist: song.map(|song| song.artist.clone()),
+                        title: song.map(|song| song.title.clone()),
+                    }
+                })
+                .collect(),
+            songs_played: queue.play_record.values().map(|record| record.count).sum(),
+            distinct_songs: queue.play_record.len(),
+            still_queued: queue.list.iter().filter(|entry| !entry.is_played()).count(),
+            bug_reports: queue.bug_reports.values().sum(),
+            average_intermission: queue.average_intermission().as_seconds_f64(),
+            intermission_count: queue.intermission_count,
+        };
+        drop(queue);
+
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir
+            .as_ref()
+            .join(format!("session-{}.json", archived_at.unix_timestamp()));
+        Self::write_file(&path, &serde_json::to_vec_pretty(&archive)?).await?;
+        Ok(path)
+    }
+
+    /// How long the song actually took on average when it was played this session.
+    pub async fn actual_duration(&self, song: i64) -> Option<Duration> {
+        self.song_queue
+            .read()
+            .await
+            .actual_duration(self.canonical(song))
+    }
+
+    pub async fn churn_report(&self) -> ChurnReport {
+        self.song_queue.read().await.churn.report()
+    }
+
+    /// Per singer statistics over the known history and the queue, longest waiting first.
+    pub async fn fairness_report(&self) -> Vec<SingerStats> {
+        let queue = self.song_queue.read().await;
+        let now = OffsetDateTime::now_utc();
+        let field = self.settings.fairness_counts;
+        let mut stats: Vec<SingerStats> = Vec::new();
+        // The first entry of each singer in `stats`, to tell who is who.
+        let mut singers: Vec<&PlaylistEntry> = Vec::new();
+        for entry in queue.play_history.iter().chain(queue.list.iter()) {
+            let position = singers
+                .iter()
+                .position(|singer| singer.same_singer_as(entry, field))
+                .unwrap_or_else(|| {
+                    singers.push(entry);
+                    stats.push(SingerStats {
+                        singer: entry.singer(field).trim().to_owned(),
+                        songs_played: 0,
+                        songs_pending: 0,
+                        total_wait: 0.0,
+                    });
+                    stats.len() - 1
+                });
+            let singer = &mut stats[position];
+            let wait_end = if let Some(started_at) = entry.started_at {
+                singer.songs_played += 1;
+                started_at
+            } else {
+                singer.songs_pending += 1;
+                now
+            };
+            singer.total_wait += (wait_end - entry.added_at).as_seconds_f64().max(0.0);
+        }
+        stats.sort_by(|a, b| b.total_wait.total_cmp(&a.total_wait));
+        stats
+    }
+
+    /// The entries that haven't started yet grouped by singer, the singer queued earliest first.
+    pub async fn pending_by_singer(&self) -> Vec<(String, Vec<PlaylistEntry>)> {
+        let queue = self.song_queue.read().await;
+        let field = self.settings.fairness_counts;
+        let mut groups: Vec<(String, Vec<PlaylistEntry>)> = Vec::new();
+        for entry in queue.list.iter().filter(|entry| entry.started_at.is_none()) {
+            match groups
+                .iter_mut()
+                .find(|(_, entries)| entries[0].same_singer_as(entry, field))
+            {
+                Some((_, entries)) => entries.push(entry.clone()),
+                None => groups.push((entry.singer(field).trim().to_owned(), vec![entry.clone()])),
+            }
+        }
+        groups
+    }
+
+    /// The entries that haven't started yet and were added within the window, newest first.
+    pub async fn recently_added(&self, within: Duration) -> Vec<PlaylistEntry> {
+        let queue = self.song_queue.read().await;
+        // A window reaching before the start of time includes everything.
+        let cutoff = OffsetDateTime::now_utc().checked_sub(within);
+        let mut entries: Vec<_> = queue
+            .list
+            .iter()
+            .filter(|entry| {
+                entry.started_at.is_none() && cutoff.is_none_or(|cutoff| entry.added_at >= cutoff)
+            })
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.added_at));
+        entries
+    }
+
+    /// How long from now until each of the singer's queued entries starts, in queue order.
+    pub async fn singer_schedule(&self, name: &str) -> Vec<(Uuid, Duration)> {
+        let queue = self.song_queue.read().await;
+        let now = OffsetDateTime::now_utc();
+        let average_intermission = queue.average_intermission();
+        let mut start = queue.baseline() + average_intermission;
+        let mut schedule = Vec::new();
+        for entry in queue.list.iter().filter(|entry| !entry.is_played()) {
+            if same_singer(entry.performer(), name) {
+                schedule.push((entry.id, (start - now).max(Duration::ZERO)));
+            }
+            start = entry.predicted_end + average_intermission;
+        }
+        schedule
+    }
+
+    /// Aggregated statistics about the session that are safe to share.
+    pub async fn analytics_export(
+        &self,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<AnalyticsExport> {
+        let queue = self.song_queue.read().await;
+        let mut plays: Vec<_> = queue
+            .play_record
+            .iter()
+            .map(|(song, record)| (*song, record.count))
+            .collect();
+        plays.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        plays.truncate(TOP_SONGS);
+
+        let songs: HashMap<_, _> = index
+            .songs_by_ids(plays.iter().map(|(song, _)| *song).collect())
+            .await?
+            .into_iter()
+            .map(|song| (song.row_id, song))
+            .collect();
+        let top_songs = plays
+            .into_iter()
+            .filter_map(|(song, plays)| {
+                songs.get(&song).map(|song| SongPlays {
+                    artist: song.artist.clone(),
+                    title: song.title.clone(),
+                    plays,
+                })
+            })
+            .collect();
+
+        Ok(AnalyticsExport {
+            songs_played: queue.play_record.values().map(|record| record.count).sum(),
+            distinct_songs: queue.play_record.len(),
+            top_songs,
+            average_intermission: queue.average_intermission().as_seconds_f64(),
+            intermission_count: queue.intermission_count,
+            songs_queued: queue.list.iter().filter(|entry| !entry.is_played()).count(),
+        })
+    }
+
+    /// Writes the final state as a full snapshot. Changes after this are still broadcast, but not
+    /// persisted, so nothing overwrites the final state. Background tasks should be stopped first.
+    pub async fn shutdown(&self, index: &dyn SongCatalog) -> anyhow::Result<()> {
+        let mut queue = self.song_queue.write().await;
+        self.flush_unrated(&mut queue, index).await?;
+        self.shut_down.store(true, Ordering::Relaxed);
+        self.write_snapshot(serde_json::to_string(&*queue)?.as_bytes())
+            .await?;
+        if self.settings.write_ahead_log {
+            Self::write_file(&Self::wal_path(&self.persist_path), &[]).await?;
+            queue.wal_records = 0;
+        }
+        Ok(())
+    }
+
+    /// Ticks every `period` until `stop` is cancelled. A tick that already started is finished first.
+    pub async fn run_ticks(
+        &self,
+        index: &dyn SongCatalog,
+        period: std::time::Duration,
+        stop: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.cancelled() => break,
+            }
+            if let Err(err) = self.tick(index).await {
+                log::error!("Playlist tick failed: {err:?}");
+            }
+        }
+    }
+
+    /// Called periodically to send out time based notifications.
+    pub async fn tick(&self, index: &dyn SongCatalog) -> anyhow::Result<()> {
+        self.tick_at(OffsetDateTime::now_utc(), index).await
+    }
+
+    async fn tick_at(&self, now: OffsetDateTime, index: &dyn SongCatalog) -> anyhow::Result<()> {
+        let mut queue = self.song_queue.write().await;
+        let mut released = false;
+        for entry in queue.list.iter_mut() {
+            if entry.held_until.is_some_and(|until| until <= now) {
+                log::info!("The hold on {} timed out", entry.id);
+                entry.held_until = None;
+                released = true;
+            }
+        }
+        let promoted = Self::promote_scheduled(&mut queue.list, now);
+        if released || promoted {
+            self.did_change(&mut queue, index).await?;
+        }
+        queue
+            .reservations
+            .retain(|reservation| reservation.expires_at > now);
+        // Listeners that went away without unsubscribing would only be noticed by the next change.
+        let listeners = queue.listeners.len();
+        queue.listeners.retain(|_, listener| !listener.is_closed());
+        if queue.listeners.len() < listeners {
+            log::debug!(
+                "Dropped {} closed listeners",
+                listeners - queue.listeners.len()
+            );
+        }
+        let average_intermission = queue.average_intermission();
+
+        let mut events = Vec::new();
+        if self.settings.up_soon_warning != 0 {
+            let threshold = now + Duration::seconds(self.settings.up_soon_warning as _);
+            let mut start = queue.baseline() + average_intermission;
+            for entry in queue.list.iter().filter(|entry| !entry.is_played()) {
+                if start > threshold {
+                    break;
+                }
+                if !queue.warned.contains(&entry.id) {
+                    events.push(Event::UpSoon {
+                        id: entry.id,
+                        singer: entry.performer().to_owned(),
+                    });
+                }
+                if entry.later {
+                    // We don't know when the entries after this one start.
+                    break;
+                }
+                start = entry.predicted_end + average_intermission;
+            }
+        }
+
+        if self.settings.preload_lookahead != 0 {
+            let threshold = now + Duration::seconds(self.settings.preload_lookahead as _);
+            let ending_soon = queue
+                .now_playing()
+                .is_some_and(|entry| entry.predicted_end <= threshold);
+            let next = queue.list.iter().find(|entry| !entry.is_played()).cloned();
+            if let Some(next) = next.filter(|next| ending_soon && queue.preloaded != Some(next.id))
+            {
+                if let Some(song) = Self::songs_by_id([&next], index).await?.remove(&next.song) {
+                    queue.preloaded = Some(next.id);
+                    events.push(Event::Preload { id: next.id, song });
+                }
+            }
+        }
+
+        let InnerPlaylist {
+            list,
+            warned,
+            listeners,
+            ..
+        } = &mut *queue;
+        // Forget about entries that are gone
+        warned.retain(|id| list.iter().any(|entry| entry.id == *id));
+        for event in events {
+            if let Event::UpSoon { id, .. } = &event {
+                warned.insert(*id);
+            }
+            let json = serde_json::to_string(&event)?;
+            for listener in listeners.values() {
+                if let Err(err) = listener.send(json.clone()) {
+                    log::error!("Failed sending event to listener: {err:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn health_check(&self, index: &dyn SongCatalog) -> HealthStatus {
+        // Creating the file here would leave an empty, unloadable playlist behind, so before the first
+        // change only the directory is checked.
+        let persist = match OpenOptions::new()
+            .append(true)
+            .open(&self.persist_path)
+            .await
+        {
+            Ok(_) => true,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let dir = match self.persist_path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                match tokio::fs::metadata(dir).await {
+                    Ok(metadata) => metadata.is_dir() && !metadata.permissions().readonly(),
+                    Err(err) => {
+                        log::error!("Health check: persist directory not accessible: {err:?}");
+                        false
+                    }
+                }
+            }
+            Err(err) => {
+                log::error!("Health check: persist path not writable: {err:?}");
+                false
+            }
+        };
+        let song_log = if let Some(song_log) = &self.song_log {
+            song_log.is_writable().await
+        } else {
+            true
+        };
+        let bug_log = self.bug_log.is_writable().await;
+        let song_count = index.song_count();
+        let index = index.is_available().await;
+
+        HealthStatus {
+            persist,
+            song_log,
+            bug_log,
+            index,
+            song_count,
+            persist_failures: self.song_queue.read().await.persist_failures,
+            log_failures: self.log_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn did_change(
+        &self,
+        inner: &mut InnerPlaylist,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<()> {
+        // update play time estimates
+        let horizon = self.settings.prediction_horizon.unwrap_or(usize::MAX);
+        let durations: HashMap<_, _> = Self::songs_by_id(
+            inner
+                .list
+                .iter()
+                .filter(|entry| !entry.is_played())
+                .take(horizon),
+            index,
+        )
+        .await?
+        .into_iter()
+        .map(|(id, song)| (id, self.song_duration(inner, &song)))
+        .collect();
+        let now = OffsetDateTime::now_utc();
+        let mut timestamp = inner.baseline();
+        let average_intermission = inner.average_intermission();
+        for (position, playlist_item) in inner
+            .list
+            .iter_mut()
+            .filter(|entry| !entry.is_played())
+            .enumerate()
+        {
+            playlist_item.later = position >= horizon;
+            if playlist_item.later {
+                // Nobody needs a precise prediction that far ahead, it can't end earlier than this though.
+                playlist_item.predicted_end = timestamp.max(now);
+            } else if let Some(&duration) = durations.get(&playlist_item.song) {
+                timestamp = timestamp
+                    .checked_add(average_intermission + duration)
+                    .unwrap_or(timestamp);
+                // Nothing in the queue can end in the past, and since the timestamp only moves forward
+                // from here on, every entry ends no earlier than the one before it.
+                timestamp = timestamp.max(now);
+                playlist_item.predicted_end = timestamp;
+            }
+        }
+
+        inner.version += 1;
+        let snapshot = VersionSnapshot::of(inner);
+        let state = self
+            .settings
+            .write_ahead_log
+            .then(|| SessionState::of(inner));
+        // The log only works if the previous version is known, otherwise a snapshot is needed.
+        let wal_record = inner
+            .recent_versions
+            .back()
+            .filter(|(version, _)| {
+                self.settings.write_ahead_log
+                    && *version + 1 == inner.version
+                    && inner.wal_records < WAL_COMPACTION
+            })
+            .map(|(_, previous)| WalRecord {
+                version: inner.version,
+                delta: previous.diff(&snapshot),
+                state: state
+                    .clone()
+                    .filter(|state| inner.logged_state.as_ref() != Some(state)),
+            });
+        let predictions = inner
+            .recent_versions
+            .back()
+            .filter(|(version, _)| {
+                self.settings.compact_predictions && *version + 1 == inner.version
+            })
+            .and_then(|(_, previous)| previous.prediction_changes(&snapshot));
+        inner.recent_versions.push_back((inner.version, snapshot));
+        while inner.recent_versions.len() > RECENT_VERSIONS {
+            inner.recent_versions.pop_front();
+        }
+        let json = serde_json::to_string(inner)?;
+        if let Some(max_entries) = self.settings.max_broadcast_entries {
+            // Changes that don't affect the visible part of the list aren't sent at all.
+            let window = inner.window(max_entries, self.settings.shown_play_history)?;
+            if inner.last_window.as_ref() != Some(&window) {
+                let payload = if let Some(mut predictions) = predictions {
+                    // Only what the listeners can see.
+                    let played = inner.list.iter().filter(|entry| entry.is_played()).count();
+                    let visible: HashSet<_> = inner
+                        .list
+                        .iter()
+                        .take(played + max_entries)
+                        .map(|entry| entry.id)
+                        .collect();
+                    predictions.retain(|prediction| visible.contains(&prediction.id));
+                    inner.predictions_payload(predictions, self.settings.relative_times)?
+                } else {
+                    inner.finish_payload(window.clone(), self.settings.relative_times)?
+                };
+                inner.last_window = Some(window);
+                inner.record_broadcast(payload.len());
+                for listener in inner.listeners.values() {
+                    listener.send(payload.clone())?;
+                }
+            }
+        } else {
+            let payload = if let Some(predictions) = predictions {
+                inner.predictions_payload(predictions, self.settings.relative_times)?
+            } else {
+                self.render(inner, SubscriptionFilter::All)?
+            };
+            inner.record_broadcast(payload.len());
+            for listener in inner.listeners.values() {
+                listener.send(payload.clone())?;
+            }
+        }
+        if self.shut_down.load(Ordering::Relaxed) {
+            log::warn!(
+                "Not persisting version {} after the shutdown",
+                inner.version
+            );
+            return Ok(());
+        }
+        let persisted = if let Some(wal_record) = wal_record {
+            let result = self.append_wal(&wal_record).await;
+            inner.wal_records = if result.is_ok() {
+                inner.wal_records + 1
+            } else {
+                // A gap in the log would break the replay, so write a full snapshot next time.
+                WAL_COMPACTION
+            };
+            result
+        } else {
+            let result = self.write_snapshot(json.as_bytes()).await;
+            if result.is_ok() && self.settings.write_ahead_log {
+                // Everything in the log is part of the snapshot now.
+                inner.wal_records = 0;
+                if let Err(err) = Self::write_file(&Self::wal_path(&self.persist_path), &[]).await {
+                    log::error!("Failed truncating the write-ahead log: {err:?}");
+                }
+            }
+            result
+        };
+        if persisted.is_ok() {
+            inner.logged_state = state;
+        }
+        if let Err(err) = persisted {
+            inner.persist_failures += 1;
+            if inner.persist_failures == FAILURE_THRESHOLD {
+                log::error!(
+                    "Persisting the playlist failed {} times in a row!",
+                    inner.persist_failures
+                );
+                let event = serde_json::to_string(&Event::PersistenceFailing {
+                    failures: inner.persist_failures,
+                })?;
+                for listener in inner.listeners.values() {
+                    if let Err(err) = listener.send(event.clone()) {
+                        log::error!("Failed sending event to listener: {err:?}");
+                    }
+                }
+            }
+            return Err(err);
+        }
+        inner.persist_failures = 0;
+
+        Ok(())
+    }
+
+    fn wal_path(persist_path: &Path) -> PathBuf {
+        persist_path.with_extension("wal")
+    }
+
+    /// Writes the playlist file. If a key is configured, the signature goes on the first line, so it
+    /// can't get out of sync with the playlist.
+    async fn write_snapshot(&self, data: &[u8]) -> anyhow::Result<()> {
+        match &self.settings.persist_key {
+            Some(key) => {
+                let mut signed = sign(key, data).into_bytes();
+                signed.push(b'\n');
+                signed.extend_from_slice(data);
+                Self::write_file(&self.persist_path, &signed).await?;
+            }
+            None => Self::write_file(&self.persist_path, data).await?,
+        }
+        Ok(())
+    }
+
+    /// With a key configured, each line starts with the signature of the record, separated by a space.
+    async fn append_wal(&self, record: &WalRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        if let Some(key) = &self.settings.persist_key {
+            let mut signed = sign(key, &line).into_bytes();
+            signed.push(b' ');
+            signed.append(&mut line);
+            line = signed;
+        }
+        line.push(b'\n');
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(Self::wal_path(&self.persist_path))
+            .await?
+            .write_all(&line)
+            .await?;
+        Ok(())
+    }
+
+    /// Applies the changes from the write-ahead log that are newer than the snapshot.
+    async fn replay_wal(
+        path: &Path,
+        inner: &mut InnerPlaylist,
+        key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let data = match tokio::fs::read_to_string(path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut replayed = 0;
+        for line in data.lines() {
+            let line = match key {
+                Some(key) => {
+                    match line.split_once(' ') {
+                        Some((signature, record)) if signature == sign(key, record.as_bytes()) => {
+                            record
+                        }
+                        _ => {
+                            log::warn!("Stopping write-ahead log replay at a record with a wrong signature");
+                            break;
+                        }
+                    }
+                }
+                None => line,
+            };
+            // The last line might be incomplete if we crashed while writing it.
+            let Ok(record) = serde_json::from_str::<WalRecord>(line) else {
+                log::warn!("Stopping write-ahead log replay at an unreadable record");
+                break;
+            };
+            if record.version <= inner.version {
+                continue;
+            }
+            if record.version != inner.version + 1 {
+                log::warn!(
+                    "Write-ahead log skips from version {} to {}, stopping replay",
+                    inner.version,
+                    record.version
+                );
+                break;
+            }
+            record.delta.apply(inner);
+            if let Some(state) = record.state {
+                state.apply(inner);
+            }
+            inner.version = record.version;
+            replayed += 1;
+        }
+        if replayed > 0 {
+            log::info!("Recovered {replayed} changes from the write-ahead log");
+        }
+        Ok(())
+    }
+
+    async fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+        let mut file = File::create(path).await?;
+        file.write_all(data).await?;
+        // Tokio finishes the write in the background otherwise, so it might not be on disk yet.
+        file.flush().await
+    }
+
+    /// The duration of the song for predictions: what it actually took this session if that is learned,
+    /// otherwise the nominal duration.
+    fn song_duration(&self, queue: &InnerPlaylist, song: &Song) -> Duration {
+        let learned = if self.settings.learn_durations {
+            queue.actual_duration(self.canonical(song.row_id))
+        } else {
+            None
+        };
+        learned.unwrap_or_else(|| self.nominal_duration(song))
+    }
+
+    /// The duration of the song, preferring the duration provider over the catalog (in the configured
+    /// unit) and replacing nonsensical values.
+    fn nominal_duration(&self, song: &Song) -> Duration {
+        let provided = self
+            .durations
+            .as_ref()
+            .and_then(|durations| durations.duration(song));
+        let seconds = provided.unwrap_or(match self.settings.duration_unit {
+            DurationUnit::Seconds => song.duration,
+            DurationUnit::Milliseconds => song.duration / 1000.0,
+            DurationUnit::Minutes => song.duration * 60.0,
+        });
+        if (0.0..=MAX_SONG_DURATION).contains(&seconds) {
+            Duration::seconds_f64(seconds)
+        } else {
+            log::warn!(
+                "Song {} has an invalid duration ({} seconds, is duration_unit set correctly?), assuming {FALLBACK_SONG_DURATION} seconds",
+                song.row_id,
+                seconds
+            );
+            Duration::seconds_f64(FALLBACK_SONG_DURATION)
         }
     }
 
-    pub async fn report_bug(
+    fn hash_password(&self, password: &str) -> String {
+        match &self.settings.password_pepper {
+            Some(pepper) => digest(format!("{pepper}{password}")),
+            None => digest(password),
+        }
+    }
+
+    /// Whether song log rows wait for the performance to be rated.
+    fn rated_song_log(&self) -> bool {
+        self.song_log.is_some()
+            && self
+                .settings
+                .song_log_columns
+                .contains(&SongLogColumn::Rating)
+    }
+
+    fn song_log_record(
         &self,
-        song: i64,
-        report: &str,
-        index: &SearchIndex,
+        queue: &InnerPlaylist,
+        played: &PlaylistEntry,
+        song: &Song,
+        rating: Option<f64>,
+    ) -> StringRecord {
+        let timestamp = played
+            .started_at
+            .unwrap_or_else(OffsetDateTime::now_utc)
+            .format(&Rfc3339)
+            .unwrap();
+        self.settings
+            .song_log_columns
+            .iter()
+            .map(|column| match column {
+                SongLogColumn::Timestamp => timestamp.clone(),
+                SongLogColumn::Artist => song.artist.clone(),
+                SongLogColumn::Title => song.title.clone(),
+                SongLogColumn::Singer => played.performer().to_owned(),
+                SongLogColumn::RequestedBy => played.requested_by.clone(),
+                SongLogColumn::SongId => played.song.to_string(),
+                SongLogColumn::EntryId => played.id.to_string(),
+                SongLogColumn::Duration => self.nominal_duration(song).as_seconds_f64().to_string(),
+                SongLogColumn::Session => queue.session.clone().unwrap_or_default(),
+                SongLogColumn::Rating => {
+                    rating.map(|rating| rating.to_string()).unwrap_or_default()
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the song log row of the performance waiting for a rating without one, e.g. because
+    /// the next song started or the night is over. Returns whether there was one.
+    async fn flush_unrated(
+        &self,
+        queue: &mut InnerPlaylist,
+        index: &dyn SongCatalog,
+    ) -> anyhow::Result<bool> {
+        let Some(unrated) = queue.unrated.take() else {
+            return Ok(false);
+        };
+        self.finish_song_log(queue, unrated, None, index).await?;
+        Ok(true)
+    }
+
+    /// Writes the song log row of a performance that was waiting for its rating.
+    async fn finish_song_log(
+        &self,
+        queue: &InnerPlaylist,
+        id: Uuid,
+        rating: Option<f64>,
+        index: &dyn SongCatalog,
     ) -> anyhow::Result<()> {
-        if !self.valid_songs.contains(&song) {
-            log::error!("Bug report for song that doesn't exist!");
+        let Some(song_log) = &self.song_log else {
+            return Ok(());
+        };
+        let Some(played) = queue
+            .play_history
+            .iter()
+            .chain(queue.list.iter())
+            .find(|entry| entry.id == id)
+        else {
+            log::error!("Can't write song log: the performance isn't in the playlist anymore!");
             return Ok(());
+        };
+        match index.songs_by_ids(vec![played.song]).await?.first() {
+            Some(song) => {
+                let record = self.song_log_record(queue, played, song, rating);
+                self.track_log_write("song log", song_log.write_record(record).await);
+            }
+            None => log::error!("Can't write song log: song not found!"),
         }
-        let songs = index.search_song(&format!("rowid:{song}"), 1)?;
-
-        if songs.is_empty() {
-            log::error!("Can't write bug log: song not found!");
-        } else {
-            let timestamp = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
-            let mut bug_log = self.bug_log.lock().await;
-            let record =
-                StringRecord::from(vec![&timestamp, &songs[0].artist, &songs[0].title, report]);
-            let mut writer = Writer::from_writer(Vec::new());
-            writer.write_record(&record).unwrap();
+        Ok(())
+    }
 
-            if let Err(err) = bug_log.write_all(&writer.into_inner().unwrap()).await {
-                log::error!("Failed writing bug log: {err:?}");
+    fn track_log_write(&self, log: &str, result: anyhow::Result<()>) {
+        match result {
+            Ok(()) => self.log_failures.store(0, Ordering::Relaxed),
+            Err(err) => {
+                log::error!("Failed writing {log}: {err:?}");
+                let failures = self.log_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures == FAILURE_THRESHOLD {
+                    log::error!("Writing logs failed {failures} times in a row!");
+                }
             }
         }
-        Ok(())
     }
+}
 
-    async fn did_change(
-        inner: &mut InnerPlaylist,
-        path: &PathBuf,
-        index: &SearchIndex,
-    ) -> anyhow::Result<()> {
-        // update play time estimates
-        let songs = index.search_song(
-            &inner
+/// A playlist file in the temporary directory for tests. It is removed together with everything
+/// written next to it when this is dropped.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct TempPlaylistFile {
+    pub path: PathBuf,
+}
+
+#[cfg(test)]
+impl TempPlaylistFile {
+    pub fn unique() -> Self {
+        Self {
+            path: std::env::temp_dir().join(format!("karaoke-test-{}.json", Uuid::new_v4())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempPlaylistFile {
+    fn drop(&mut self) {
+        for extension in ["json", "wal", "tampered", "tampered.wal"] {
+            let _ = std::fs::remove_file(self.path.with_extension(extension));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use super::*;
+    use crate::{catalog::MemoryCatalog, log_sink::ChannelSink};
+
+    /// A playlist persisted to a temporary file, with songs 1 to 10 in the catalog.
+    struct Fixture {
+        playlist: Playlist,
+        catalog: MemoryCatalog,
+        song_log: UnboundedReceiver<StringRecord>,
+        bug_log: UnboundedReceiver<StringRecord>,
+        file: Arc<TempPlaylistFile>,
+    }
+
+    impl Fixture {
+        async fn new(settings: Queue) -> Self {
+            Self::open(Arc::new(TempPlaylistFile::unique()), settings)
+                .await
+                .unwrap()
+        }
+
+        async fn open(file: Arc<TempPlaylistFile>, settings: Queue) -> anyhow::Result<Self> {
+            let catalog = MemoryCatalog::new((1..=10).map(song));
+            let (sender, song_log) = unbounded_channel();
+            let (bug_sender, bug_log) = unbounded_channel();
+            let playlist = Playlist::load(
+                &file.path,
+                catalog.ids(),
+                Some(Box::new(ChannelSink::new(sender))),
+                Box::new(ChannelSink::new(bug_sender)),
+                None,
+                None,
+                settings,
+            )
+            .await?;
+            Ok(Self {
+                playlist,
+                catalog,
+                song_log,
+                bug_log,
+                file,
+            })
+        }
+
+        /// Loads the playlist again from the same file.
+        async fn reopen(&self, settings: Queue) -> anyhow::Result<Self> {
+            Self::open(self.file.clone(), settings).await
+        }
+
+        async fn add(&self, song: i64, singer: &str) -> Uuid {
+            self.add_with_password(song, singer, None).await
+        }
+
+        async fn add_with_password(&self, song: i64, singer: &str, password: Option<&str>) -> Uuid {
+            let options = AddOptions {
+                password: password.map(str::to_owned),
+                ..Default::default()
+            };
+            match self
+                .playlist
+                .add(song, singer.to_owned(), options, &self.catalog)
+                .await
+                .unwrap()
+            {
+                AddResult::Added(id)
+                | AddResult::AddedSimilar { id, .. }
+                | AddResult::AddedSameName { id, .. } => id,
+                result => panic!("Adding song {song} failed: {result:?}"),
+            }
+        }
+
+        async fn singers(&self) -> Vec<String> {
+            let queue = self.playlist.song_queue.read().await;
+            queue
                 .list
                 .iter()
-                .map(|entry| format!("rowid:{}", entry.song))
-                .collect::<Vec<_>>()
-                .join(" OR "),
-            inner.list.len(),
-        )?;
-        let mut timestamp = inner
-            .play_history
-            .back()
-            .map(|entry| entry.predicted_end)
-            .unwrap_or_else(OffsetDateTime::now_utc);
-        let average_intermission = inner
-            .intermission_duration
-            .checked_div(inner.intermission_count as _)
-            .unwrap_or_default();
-        for playlist_item in &mut inner.list {
-            if let Some(song) = songs.iter().find(|&song| song.row_id == playlist_item.song) {
-                timestamp += average_intermission + Duration::seconds_f64(song.duration);
-                playlist_item.predicted_end = timestamp;
+                .map(|entry| entry.requested_by.clone())
+                .collect()
+        }
+
+        async fn listen(&self) -> UnboundedReceiver<String> {
+            let (sender, receiver) = unbounded_channel();
+            self.playlist.subscribe(sender, None).await.unwrap();
+            receiver
+        }
+    }
+
+    /// A ten minute song.
+    fn song(id: i64) -> Song {
+        Song {
+            duration: 600.0,
+            ..MemoryCatalog::song(id, &format!("Artist {id}"), &format!("Title {id}"))
+        }
+    }
+
+    /// The events of the given kind received so far, skipping everything else.
+    fn received_events(
+        receiver: &mut UnboundedReceiver<String>,
+        kind: &str,
+    ) -> Vec<serde_json::Value> {
+        let mut events = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            let value: serde_json::Value = serde_json::from_str(&message).unwrap();
+            if value["event"] == kind {
+                events.push(value);
             }
         }
+        events
+    }
 
-        let json = serde_json::to_string(inner)?;
-        for listener in inner.listeners.values() {
-            listener.send(json.clone())?;
+    /// The ids of the up soon warnings received so far.
+    fn up_soon_warnings(receiver: &mut UnboundedReceiver<String>) -> Vec<Uuid> {
+        received_events(receiver, "upSoon")
+            .into_iter()
+            .map(|event| serde_json::from_value(event["id"].clone()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn up_soon_warning_fires_once_per_entry() {
+        let fixture = Fixture::new(Queue {
+            up_soon_warning: 120,
+            ..Default::default()
+        })
+        .await;
+        let first = fixture.add(1, "A").await;
+        let second = fixture.add(2, "B").await;
+        let third = fixture.add(3, "C").await;
+        let mut receiver = fixture.listen().await;
+        let now = OffsetDateTime::now_utc();
+
+        fixture
+            .playlist
+            .tick_at(now, &fixture.catalog)
+            .await
+            .unwrap();
+        fixture
+            .playlist
+            .tick_at(now, &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(up_soon_warnings(&mut receiver), [first]);
+
+        fixture
+            .playlist
+            .tick_at(now + Duration::minutes(10), &fixture.catalog)
+            .await
+            .unwrap();
+        fixture
+            .playlist
+            .tick_at(now + Duration::minutes(11), &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(up_soon_warnings(&mut receiver), [second]);
+
+        fixture
+            .playlist
+            .tick_at(now + Duration::minutes(30), &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(up_soon_warnings(&mut receiver), [third]);
+    }
+
+    #[tokio::test]
+    async fn up_soon_warning_is_off_by_default() {
+        let fixture = Fixture::new(Queue::default()).await;
+        fixture.add(1, "A").await;
+        let mut receiver = fixture.listen().await;
+
+        fixture
+            .playlist
+            .tick_at(
+                OffsetDateTime::now_utc() + Duration::hours(1),
+                &fixture.catalog,
+            )
+            .await
+            .unwrap();
+        assert!(up_soon_warnings(&mut receiver).is_empty());
+    }
+
+    /// The CSV row without its leading timestamp.
+    fn row_fields(record: StringRecord) -> Vec<String> {
+        record.iter().skip(1).map(str::to_owned).collect()
+    }
+
+    #[tokio::test]
+    async fn play_and_report_bug_write_through_the_sinks() {
+        let mut fixture = Fixture::new(Queue::default()).await;
+        let id = fixture.add(2, "A").await;
+        assert!(fixture.playlist.play(id, &fixture.catalog).await.unwrap());
+        fixture
+            .playlist
+            .report_bug(3, "Lyrics are off", &fixture.catalog)
+            .await
+            .unwrap();
+
+        let played = fixture.song_log.try_recv().unwrap();
+        assert_eq!(row_fields(played), ["Artist 2", "Title 2"]);
+        assert!(fixture.song_log.try_recv().is_err());
+        let bug = fixture.bug_log.try_recv().unwrap();
+        assert_eq!(row_fields(bug), ["Artist 3", "Title 3", "Lyrics are off"]);
+        assert!(fixture.bug_log.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_entry_survives_reorder_attempts() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let a = fixture.add(1, "A").await;
+        let b = fixture.add(2, "B").await;
+        let c = fixture.add(3, "C").await;
+        assert!(fixture
+            .playlist
+            .set_pinned(b, true, &fixture.catalog)
+            .await
+            .unwrap());
+
+        let catalog = &fixture.catalog;
+        assert_eq!(
+            fixture.playlist.swap(a, b, catalog).await.unwrap(),
+            EditResult::Pinned
+        );
+        assert_eq!(
+            fixture.playlist.move_top(b, catalog).await.unwrap(),
+            EditResult::Pinned
+        );
+        assert_eq!(
+            fixture.playlist.move_after(b, c, catalog).await.unwrap(),
+            EditResult::Pinned
+        );
+        assert_eq!(
+            fixture.playlist.remove(b, catalog).await.unwrap(),
+            EditResult::Pinned
+        );
+        assert_eq!(fixture.singers().await, ["A", "B", "C"]);
+
+        assert!(fixture
+            .playlist
+            .set_pinned(b, false, catalog)
+            .await
+            .unwrap());
+        assert_eq!(
+            fixture.playlist.move_top(b, catalog).await.unwrap(),
+            EditResult::Changed
+        );
+        assert_eq!(fixture.singers().await, ["B", "A", "C"]);
+    }
+
+    #[test]
+    fn reordering_keeps_pins_at_their_index() {
+        let mut list: VecDeque<_> = ["A", "B", "C", "D"]
+            .into_iter()
+            .map(|singer| PlaylistEntry {
+                pinned: singer == "B",
+                ..PlaylistEntry::new(1, singer.to_owned(), None, OffsetDateTime::now_utc())
+            })
+            .collect();
+
+        Playlist::keep_pins_in_place(&mut list, |list| list.make_contiguous().reverse());
+
+        let singers: Vec<_> = list
+            .iter()
+            .map(|entry| entry.requested_by.as_str())
+            .collect();
+        assert_eq!(singers, ["D", "B", "C", "A"]);
+    }
+
+    #[tokio::test]
+    async fn preload_fires_once_for_the_next_song() {
+        let fixture = Fixture::new(Queue {
+            preload_lookahead: 30,
+            ..Default::default()
+        })
+        .await;
+        let first = fixture.add(1, "A").await;
+        let second = fixture.add(2, "B").await;
+        fixture.add(3, "C").await;
+        assert!(fixture
+            .playlist
+            .play(first, &fixture.catalog)
+            .await
+            .unwrap());
+        let mut receiver = fixture.listen().await;
+        let now = OffsetDateTime::now_utc();
+
+        fixture
+            .playlist
+            .tick_at(now, &fixture.catalog)
+            .await
+            .unwrap();
+        assert!(received_events(&mut receiver, "preload").is_empty());
+
+        // The first song ends ten minutes from now.
+        let ending = now + Duration::minutes(10) - Duration::seconds(20);
+        fixture
+            .playlist
+            .tick_at(ending, &fixture.catalog)
+            .await
+            .unwrap();
+        fixture
+            .playlist
+            .tick_at(ending, &fixture.catalog)
+            .await
+            .unwrap();
+        let preloads = received_events(&mut receiver, "preload");
+        assert_eq!(preloads.len(), 1);
+        assert_eq!(preloads[0]["id"], second.to_string());
+        assert_eq!(preloads[0]["song"]["rowId"], 2);
+    }
+
+    #[tokio::test]
+    async fn play_front_plays_the_first_entry_until_the_queue_is_empty() {
+        let fixture = Fixture::new(Queue::default()).await;
+        assert_eq!(
+            fixture.playlist.play_front(&fixture.catalog).await.unwrap(),
+            PlayResult::QueueEmpty
+        );
+
+        let first = fixture.add(1, "A").await;
+        fixture.add(2, "B").await;
+        let PlayResult::Played(played) =
+            fixture.playlist.play_front(&fixture.catalog).await.unwrap()
+        else {
+            panic!("Nothing was played");
+        };
+        assert_eq!(played.id, first);
+        assert_eq!(fixture.singers().await, ["B"]);
+
+        fixture.playlist.play_front(&fixture.catalog).await.unwrap();
+        assert_eq!(
+            fixture.playlist.play_front(&fixture.catalog).await.unwrap(),
+            PlayResult::QueueEmpty
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_singer_can_be_restored_during_the_grace_period() {
+        let fixture = Fixture::new(Queue::default()).await;
+        fixture.add(1, "A").await;
+        fixture.add(2, "B").await;
+        fixture.add(3, "a ").await;
+
+        let removed = fixture
+            .playlist
+            .remove_singer("A", None, &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(fixture.singers().await, ["B"]);
+        // The trash is only for the host.
+        let mut receiver = fixture.listen().await;
+        let payload: serde_json::Value =
+            serde_json::from_str(&receiver.try_recv().unwrap()).unwrap();
+        assert!(payload.get("trash").is_none());
+
+        let restored = fixture
+            .playlist
+            .restore_singer("A", None, &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(restored, 2);
+        assert_eq!(fixture.singers().await, ["A", "B", "a "]);
+    }
+
+    #[tokio::test]
+    async fn removed_singer_is_gone_after_the_grace_period() {
+        let fixture = Fixture::new(Queue {
+            singer_restore_grace: 0,
+            ..Default::default()
+        })
+        .await;
+        fixture.add(1, "A").await;
+        fixture
+            .playlist
+            .remove_singer("A", None, &fixture.catalog)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+        let restored = fixture
+            .playlist
+            .restore_singer("A", None, &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(restored, 0);
+        assert!(fixture.singers().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_lock_the_entry() {
+        let fixture = Fixture::new(Queue {
+            password_attempts: 3,
+            ..Default::default()
+        })
+        .await;
+        let id = fixture.add_with_password(1, "A", Some("1234")).await;
+        let remove = |password: &str| {
+            fixture
+                .playlist
+                .remove_if_password_correct(id, password.to_owned(), &fixture.catalog)
+        };
+
+        for guess in ["0000", "1111", "2222"] {
+            assert_eq!(remove(guess).await.unwrap(), EditResult::Unchanged);
         }
-        let mut file = File::create(path).await?;
-        file.write_all(json.as_bytes()).await?;
+        assert_eq!(remove("1234").await.unwrap(), EditResult::Locked);
+        assert_eq!(fixture.singers().await, ["A"]);
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn correct_password_removes_the_entry_unless_pinned() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let id = fixture.add_with_password(1, "A", Some("1234")).await;
+        let catalog = &fixture.catalog;
+        fixture
+            .playlist
+            .set_pinned(id, true, catalog)
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .playlist
+                .remove_if_password_correct(id, "1234".to_owned(), catalog)
+                .await
+                .unwrap(),
+            EditResult::Pinned
+        );
+
+        fixture
+            .playlist
+            .set_pinned(id, false, catalog)
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture
+                .playlist
+                .remove_if_password_correct(id, "1234".to_owned(), catalog)
+                .await
+                .unwrap(),
+            EditResult::Changed
+        );
+        assert!(fixture.singers().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn password_hashes_are_not_broadcast() {
+        let fixture = Fixture::new(Queue::default()).await;
+        fixture.add(1, "A").await;
+        let version = fixture.playlist.full_state().await.version;
+        fixture.add_with_password(2, "B", Some("1234")).await;
+        let mut receiver = fixture.listen().await;
+
+        let payload = receiver.try_recv().unwrap();
+        assert!(!payload.contains("passwordHash"));
+        let ChangesSince::Delta { delta, .. } =
+            fixture.playlist.changes_since(version).await.unwrap()
+        else {
+            panic!("Expected a delta");
+        };
+        assert!(delta
+            .changed
+            .iter()
+            .all(|entry| entry.password_hash.is_none()));
+    }
+
+    /// Feeds everything the listener received so far to the follower.
+    fn mirror(follower: &mut PlaylistFollower, receiver: &mut UnboundedReceiver<String>) {
+        while let Ok(message) = receiver.try_recv() {
+            follower.apply_broadcast(&message).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn follower_mirrors_the_primary() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let mut receiver = fixture.listen().await;
+        let mut follower = PlaylistFollower::default();
+        mirror(&mut follower, &mut receiver);
+        assert_eq!(follower.version(), Some(0));
+        assert!(follower.is_empty());
+
+        let first = fixture.add(1, "A").await;
+        let second = fixture.add(2, "B").await;
+        mirror(&mut follower, &mut receiver);
+        assert_eq!(follower.len(), 2);
+        assert_eq!(follower.up_next().map(|entry| entry.id), Some(first));
+
+        fixture
+            .playlist
+            .play(first, &fixture.catalog)
+            .await
+            .unwrap();
+        mirror(&mut follower, &mut receiver);
+        assert_eq!(follower.now_playing().map(|entry| entry.id), Some(first));
+        assert_eq!(follower.up_next().map(|entry| entry.id), Some(second));
+        assert_eq!(
+            serde_json::to_value(follower.state()).unwrap(),
+            serde_json::to_value(fixture.playlist.full_state().await).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn follower_knows_the_full_length_of_a_capped_list() {
+        let fixture = Fixture::new(Queue {
+            max_broadcast_entries: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let mut receiver = fixture.listen().await;
+        let mut follower = PlaylistFollower::default();
+        for song in 1..=3 {
+            fixture.add(song, "A").await;
+        }
+
+        mirror(&mut follower, &mut receiver);
+        assert_eq!(follower.len(), 3);
+        assert_eq!(follower.state().list.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tick_prunes_closed_listeners() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let _alive = fixture.listen().await;
+        drop(fixture.listen().await);
+        drop(fixture.listen().await);
+        assert_eq!(fixture.playlist.song_queue.read().await.listeners.len(), 3);
+
+        fixture
+            .playlist
+            .tick_at(OffsetDateTime::now_utc(), &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(fixture.playlist.song_queue.read().await.listeners.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_subscribes_and_unsubscribes_settle() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let playlist = &fixture.playlist;
+        let churn = futures_util::future::join_all((0..50).map(|i| async move {
+            let (sender, receiver) = unbounded_channel();
+            let id = playlist.subscribe(sender, None).await.unwrap();
+            if i % 2 == 0 {
+                playlist.unsubscribe(id).await;
+            }
+            receiver
+        }));
+        let changes = async {
+            for song in 1..=5 {
+                fixture.add(song, "A").await;
+            }
+        };
+
+        let (receivers, ()) = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            futures_util::future::join(churn, changes),
+        )
+        .await
+        .expect("subscribing deadlocked");
+        assert_eq!(playlist.song_queue.read().await.listeners.len(), 25);
+        // Every remaining listener ends up with the latest playlist, however the calls interleaved.
+        let version = playlist.full_state().await.version;
+        for mut receiver in receivers.into_iter().skip(1).step_by(2) {
+            let mut last = None;
+            while let Ok(message) = receiver.try_recv() {
+                last = Some(message);
+            }
+            let last: serde_json::Value = serde_json::from_str(&last.unwrap()).unwrap();
+            assert_eq!(last["version"], version);
+        }
+    }
+
+    #[tokio::test]
+    async fn nothing_is_persisted_after_the_shutdown() {
+        let fixture = Fixture::new(Queue {
+            write_ahead_log: true,
+            ..Default::default()
+        })
+        .await;
+        let mut receiver = fixture.listen().await;
+        let stop = CancellationToken::new();
+        let ticks = fixture.playlist.run_ticks(
+            &fixture.catalog,
+            std::time::Duration::from_millis(1),
+            stop.clone(),
+        );
+        let changes = async {
+            fixture.add(1, "A").await;
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            stop.cancel();
+        };
+        tokio::join!(ticks, changes);
+        fixture.playlist.shutdown(&fixture.catalog).await.unwrap();
+        let persisted = std::fs::read_to_string(&fixture.file.path).unwrap();
+        let state: serde_json::Value = serde_json::from_str(&persisted).unwrap();
+        assert_eq!(state["list"][0]["requestedBy"], "A");
+        assert!(std::fs::read(fixture.file.path.with_extension("wal"))
+            .unwrap()
+            .is_empty());
+
+        // A background task that's still running can't clobber the final state.
+        fixture.add(2, "B").await;
+        fixture
+            .playlist
+            .tick_at(OffsetDateTime::now_utc(), &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&fixture.file.path).unwrap(),
+            persisted
+        );
+        assert!(std::fs::read(fixture.file.path.with_extension("wal"))
+            .unwrap()
+            .is_empty());
+        // Listeners still hear about it.
+        let last = std::iter::from_fn(|| receiver.try_recv().ok())
+            .last()
+            .unwrap();
+        assert!(last.contains("\"B\""));
+    }
+
+    fn signed(tampered_playlist: TamperedPlaylistPolicy) -> Queue {
+        Queue {
+            persist_key: Some("secret".to_owned()),
+            tampered_playlist,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_playlist_survives_a_restart() {
+        let fixture = Fixture::new(signed(TamperedPlaylistPolicy::Refuse)).await;
+        fixture.add(1, "Alice").await;
+        let reopened = fixture
+            .reopen(signed(TamperedPlaylistPolicy::Refuse))
+            .await
+            .unwrap();
+        assert_eq!(reopened.singers().await, ["Alice"]);
+    }
+
+    #[tokio::test]
+    async fn tampered_playlist_is_refused_or_discarded() {
+        let fixture = Fixture::new(signed(TamperedPlaylistPolicy::Refuse)).await;
+        fixture.add(1, "Alice").await;
+        let data = std::fs::read_to_string(&fixture.file.path).unwrap();
+        std::fs::write(&fixture.file.path, data.replace("Alice", "Mallory")).unwrap();
+
+        assert!(fixture
+            .reopen(signed(TamperedPlaylistPolicy::Refuse))
+            .await
+            .is_err());
+        let discarded = fixture
+            .reopen(signed(TamperedPlaylistPolicy::Discard))
+            .await
+            .unwrap();
+        assert!(discarded.singers().await.is_empty());
+        assert!(fixture.file.path.with_extension("tampered").exists());
+    }
+
+    #[tokio::test]
+    async fn batch_applies_all_operations() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let a = fixture.add(1, "A").await;
+        let b = fixture.add(2, "B").await;
+        let c = fixture.add(3, "C").await;
+        let version = fixture.playlist.full_state().await.version;
+
+        let result = fixture
+            .playlist
+            .batch(
+                vec![QueueOp::MoveTop { id: c }, QueueOp::Swap { id1: a, id2: b }],
+                &fixture.catalog,
+            )
+            .await
+            .unwrap();
+
+        assert!(result.applied);
+        assert_eq!(result.results, [EditResult::Changed, EditResult::Changed]);
+        assert_eq!(fixture.singers().await, ["C", "B", "A"]);
+        // A single update for the whole batch.
+        assert_eq!(fixture.playlist.full_state().await.version, version + 1);
+    }
+
+    #[tokio::test]
+    async fn batch_with_an_invalid_operation_changes_nothing() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let a = fixture.add(1, "A").await;
+        let b = fixture.add(2, "B").await;
+        let version = fixture.playlist.full_state().await.version;
+
+        let result = fixture
+            .playlist
+            .batch(
+                vec![
+                    QueueOp::MoveTop { id: b },
+                    QueueOp::Remove { id: Uuid::new_v4() },
+                    QueueOp::Remove { id: a },
+                ],
+                &fixture.catalog,
+            )
+            .await
+            .unwrap();
+
+        assert!(!result.applied);
+        assert_eq!(result.results, [EditResult::Changed, EditResult::Unchanged]);
+        assert_eq!(fixture.singers().await, ["A", "B"]);
+        assert_eq!(fixture.playlist.full_state().await.version, version);
+    }
+
+    #[tokio::test]
+    async fn new_songs_keep_the_gap_to_the_singers_previous_one() {
+        let fixture = Fixture::new(Queue {
+            min_songs_between_same_singer: 2,
+            ..Default::default()
+        })
+        .await;
+        for (song, singer) in [(1, "Alice"), (2, "Bob"), (3, "Carol"), (4, "Dave")] {
+            fixture.add(song, singer).await;
+        }
+        // The priority would put it first, ahead of Alice's other song.
+        let options = AddOptions {
+            priority: 1,
+            ..Default::default()
+        };
+        let result = fixture
+            .playlist
+            .add(5, "Alice".to_owned(), options, &fixture.catalog)
+            .await
+            .unwrap();
+        assert!(matches!(result, AddResult::Added(_)));
+        assert_eq!(
+            fixture.singers().await,
+            ["Alice", "Bob", "Carol", "Alice", "Dave"]
+        );
+    }
+
+    #[tokio::test]
+    async fn add_many_publishes_the_batch_once() {
+        let fixture = Fixture::new(Queue::default()).await;
+        let version = fixture.playlist.full_state().await.version;
+        let songs = [(1, "Alice"), (99, "Bob"), (2, "Carol")]
+            .into_iter()
+            .map(|(song, singer)| BulkAdd {
+                song,
+                singer: singer.to_owned(),
+            })
+            .collect();
+
+        let report = fixture
+            .playlist
+            .add_many(songs, &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!((report.added, report.rejected), (2, 1));
+        assert!(matches!(report.results[1], AddResult::UnknownSong));
+        assert_eq!(fixture.singers().await, ["Alice", "Carol"]);
+        assert_eq!(fixture.playlist.full_state().await.version, version + 1);
+    }
+
+    fn rated_log() -> Queue {
+        Queue {
+            song_log_columns: vec![
+                SongLogColumn::SongId,
+                SongLogColumn::Session,
+                SongLogColumn::Rating,
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn unrated_song_is_logged_when_the_session_ends() {
+        let mut fixture = Fixture::new(rated_log()).await;
+        fixture.add(1, "Alice").await;
+        fixture
+            .playlist
+            .start_session(Some("Friday".to_owned()), &fixture.catalog)
+            .await
+            .unwrap();
+        fixture.playlist.play_front(&fixture.catalog).await.unwrap();
+        assert!(fixture.song_log.try_recv().is_err());
+
+        fixture
+            .playlist
+            .start_session(Some("Saturday".to_owned()), &fixture.catalog)
+            .await
+            .unwrap();
+        let record = fixture.song_log.try_recv().unwrap();
+        assert_eq!(record, StringRecord::from(vec!["1", "Friday", ""]));
+    }
+
+    #[tokio::test]
+    async fn unrated_song_is_logged_on_shutdown() {
+        let mut fixture = Fixture::new(rated_log()).await;
+        fixture.add(1, "Alice").await;
+        fixture.playlist.play_front(&fixture.catalog).await.unwrap();
+
+        fixture.playlist.shutdown(&fixture.catalog).await.unwrap();
+        let record = fixture.song_log.try_recv().unwrap();
+        assert_eq!(record, StringRecord::from(vec!["1", "", ""]));
+        assert!(fixture.song_log.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn same_song_as_the_one_playing_is_similar() {
+        let fixture = Fixture::new(Queue {
+            similar_songs: SimilarSongPolicy::Warn,
+            ..Default::default()
+        })
+        .await;
+        let playing = fixture.add(1, "Alice").await;
+        fixture.playlist.play_front(&fixture.catalog).await.unwrap();
+
+        let result = fixture
+            .playlist
+            .add(1, "Bob".to_owned(), AddOptions::default(), &fixture.catalog)
+            .await
+            .unwrap();
+        assert!(matches!(result, AddResult::AddedSimilar { similar, .. } if similar == playing));
+    }
+
+    #[tokio::test]
+    async fn only_plausible_missing_songs_are_counted() {
+        let fixture = Fixture::new(Queue::default()).await;
+        // Song 5 was removed from the catalog, it might come back.
+        fixture
+            .playlist
+            .refresh_valid_songs((1..=10).filter(|id| *id != 5), &fixture.catalog)
+            .await
+            .unwrap();
+        for song in [5, 5, 0, -3, 11, i64::MAX] {
+            let result = fixture
+                .playlist
+                .add(
+                    song,
+                    "Alice".to_owned(),
+                    AddOptions::default(),
+                    &fixture.catalog,
+                )
+                .await
+                .unwrap();
+            assert!(matches!(result, AddResult::UnknownSong));
+        }
+        fixture.add(1, "Alice").await;
+        assert_eq!(fixture.playlist.missing_song_requests().await, [(5, 2)]);
+        assert!(!fixture
+            .playlist
+            .render_payload(&SubscriptionFilter::All)
+            .await
+            .contains("missingRequests"));
+
+        // The catalog grew, so song 11 might be missing now.
+        fixture
+            .playlist
+            .refresh_valid_songs((1..=12).filter(|id| *id != 11), &fixture.catalog)
+            .await
+            .unwrap();
+        fixture
+            .playlist
+            .add(
+                11,
+                "Alice".to_owned(),
+                AddOptions::default(),
+                &fixture.catalog,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            fixture.playlist.missing_song_requests().await,
+            [(5, 2), (11, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn write_ahead_log_recovers_the_statistics() {
+        let settings = || Queue {
+            write_ahead_log: true,
+            ..Default::default()
+        };
+        let fixture = Fixture::new(settings()).await;
+        // The first change after loading writes a snapshot, the next ones go to the log.
+        fixture.add(1, "Alice").await;
+        fixture
+            .playlist
+            .report_bug(2, "No sound", &fixture.catalog)
+            .await
+            .unwrap();
+        fixture.add(3, "Bob").await;
+
+        let reopened = fixture.reopen(settings()).await.unwrap();
+        assert_eq!(reopened.singers().await, ["Alice", "Bob"]);
+        assert_eq!(reopened.playlist.top_buggy_songs(10).await, [(2, 1)]);
+    }
+
+    #[tokio::test]
+    async fn singers_are_removed_by_id_without_publishing_it() {
+        let fixture = Fixture::new(Queue::default()).await;
+        for (song, user) in [(1, "alice-1"), (2, "alice-2")] {
+            let options = AddOptions {
+                singer_id: Some(user.to_owned()),
+                ..Default::default()
+            };
+            fixture
+                .playlist
+                .add(song, "Alice".to_owned(), options, &fixture.catalog)
+                .await
+                .unwrap();
+        }
+        assert!(!fixture
+            .playlist
+            .render_payload(&SubscriptionFilter::All)
+            .await
+            .contains("alice-1"));
+
+        let removed = fixture
+            .playlist
+            .remove_singer("Alice", Some("alice-1"), &fixture.catalog)
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+        let state = fixture.playlist.full_state().await;
+        assert_eq!(state.list.len(), 1);
+        assert_eq!(state.list[0].song, 2);
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_the_rendered_payload() {
+        let fixture = Fixture::new(Queue::default()).await;
+        fixture.add(1, "A").await;
+        fixture.add(2, "B").await;
+        let mut receiver = fixture.listen().await;
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            fixture
+                .playlist
+                .render_payload(&SubscriptionFilter::All)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn payload_only_has_what_listeners_may_see() {
+        let fixture = Fixture::new(Queue::default()).await;
+        fixture.add_with_password(1, "A", Some("1234")).await;
+        fixture.add(2, "B").await;
+        fixture
+            .playlist
+            .report_bug(1, "Lyrics are off", &fixture.catalog)
+            .await
+            .unwrap();
+        fixture
+            .playlist
+            .add(99, "C".to_owned(), AddOptions::default(), &fixture.catalog)
+            .await
+            .unwrap();
+        fixture
+            .playlist
+            .remove_singer("B", None, &fixture.catalog)
+            .await
+            .unwrap();
+
+        let payload: serde_json::Value = serde_json::from_str(
+            &fixture
+                .playlist
+                .render_payload(&SubscriptionFilter::All)
+                .await,
+        )
+        .unwrap();
+        let mut keys: Vec<_> = payload.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "addsClosed",
+                "intermissionCount",
+                "intermissionDuration",
+                "list",
+                "playHistory",
+                "version"
+            ]
+        );
+        assert!(payload["list"][0].get("passwordHash").is_none());
+
+        fixture.add(3, "C").await;
+        let next: serde_json::Value = serde_json::from_str(
+            &fixture
+                .playlist
+                .render_payload(&SubscriptionFilter::Next(1))
+                .await,
+        )
+        .unwrap();
+        assert_eq!(next["list"].as_array().unwrap().len(), 1);
+        assert_eq!(next["totalLength"], 2);
     }
 }
@@ -1,11 +1,14 @@
+use std::{ops::Bound, time::Duration};
+
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tantivy::{
     collector::{Collector, TopDocs},
-    query::{AllQuery, Query, QueryParser},
-    schema::{Field, Schema, FAST, INDEXED, STORED, STRING, TEXT},
+    query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, FAST, INDEXED, STORED, STRING, TEXT},
+    tokenizer::{AsciiFoldingFilter, LowerCaser, SimpleTokenizer, TextAnalyzer, TokenStream},
     DocAddress, DocId, Document, Index, IndexReader, IndexSettings, IndexSortByField,
-    SegmentReader,
+    SegmentReader, Term,
 };
 
 use crate::Pagination;
@@ -24,12 +27,29 @@ pub struct Song {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub lyrics: Option<String>,
     pub duet: bool,
+    pub explicit: bool,
     #[serde(default)]
     pub cover_path: Option<String>,
     pub audio_path: String,
+    /// Beats per minute, if the database knows them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<f64>,
+}
+
+/// Which field a search looks at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchField {
+    /// Artist, title, language, year and lyrics.
+    #[default]
+    Any,
+    Artist,
+    Title,
 }
 
+#[derive(Clone)]
 pub struct SearchIndex {
+    order_field: Field,
     rowid_field: Field,
     title_field: Field,
     artist_field: Field,
@@ -38,15 +58,23 @@ pub struct SearchIndex {
     lyrics_field: Field,
     duration_field: Field,
     duet_field: Field,
+    explicit_field: Field,
     cover_field: Field,
     audio_field: Field,
+    tempo_field: Field,
 
+    index: Index,
     reader: IndexReader,
     query_parser: QueryParser,
+    /// Hide explicit songs from all searches.
+    family_friendly: bool,
 }
 
 impl SearchIndex {
-    pub fn new<'a>(input: impl IntoIterator<Item = &'a Song>) -> anyhow::Result<Self> {
+    pub fn new<'a>(
+        input: impl IntoIterator<Item = &'a Song>,
+        family_friendly: bool,
+    ) -> anyhow::Result<Self> {
         let mut schema_builder = Schema::builder();
         let order_field = schema_builder.add_u64_field("order", STORED | FAST);
         let rowid_field = schema_builder.add_i64_field("rowid", INDEXED | STORED);
@@ -55,10 +83,12 @@ impl SearchIndex {
         let language_field = schema_builder.add_text_field("language", TEXT | STORED);
         let year_field = schema_builder.add_text_field("year", STRING | STORED);
         let lyrics_field = schema_builder.add_text_field("lyrics", TEXT | STORED);
-        let duration_field = schema_builder.add_f64_field("duration", STORED);
+        let duration_field = schema_builder.add_f64_field("duration", INDEXED | FAST | STORED);
         let duet_field = schema_builder.add_bool_field("duet", INDEXED | STORED);
+        let explicit_field = schema_builder.add_bool_field("explicit", INDEXED | STORED);
         let cover_field = schema_builder.add_text_field("cover", STORED);
         let audio_field = schema_builder.add_text_field("audio", STORED);
+        let tempo_field = schema_builder.add_f64_field("tempo", STORED);
         let schema = schema_builder.build();
 
         let mut index = Index::builder()
@@ -73,34 +103,6 @@ impl SearchIndex {
             .create_in_ram()?;
         index.set_default_multithread_executor()?;
 
-        let mut index_writer = index.writer(50_000_000)?;
-
-        for (order, song) in input.into_iter().enumerate() {
-            let mut doc = Document::new();
-            doc.add_i64(rowid_field, song.row_id);
-            doc.add_u64(order_field, order as _);
-            doc.add_text(title_field, song.title.clone());
-            doc.add_text(artist_field, song.artist.clone());
-            doc.add_f64(duration_field, song.duration as _);
-            if let Some(song_language) = &song.language {
-                doc.add_text(language_field, song_language.to_owned());
-            }
-            if let Some(song_year) = song.year {
-                doc.add_text(year_field, song_year.to_string());
-            }
-            if let Some(song_lyrics) = &song.lyrics {
-                doc.add_text(lyrics_field, song_lyrics);
-            }
-            doc.add_bool(duet_field, song.duet);
-            if let Some(cover) = &song.cover_path {
-                doc.add_text(cover_field, cover);
-            }
-            doc.add_text(audio_field, &song.audio_path);
-            index_writer.add_document(doc)?;
-        }
-
-        index_writer.commit()?;
-
         let reader = index.reader()?;
 
         let mut query_parser = QueryParser::for_index(
@@ -118,7 +120,8 @@ impl SearchIndex {
         query_parser.set_field_boost(artist_field, 2.0);
         query_parser.set_conjunction_by_default();
 
-        Ok(Self {
+        let search_index = Self {
+            order_field,
             rowid_field,
             title_field,
             artist_field,
@@ -127,11 +130,132 @@ impl SearchIndex {
             lyrics_field,
             duration_field,
             duet_field,
+            explicit_field,
             cover_field,
             audio_field,
+            tempo_field,
+            index,
             reader,
             query_parser,
-        })
+            family_friendly,
+        };
+        search_index.replace_songs(input)?;
+        Ok(search_index)
+    }
+
+    /// Replaces all songs in the index, e.g. after the database was updated.
+    /// All clones of this index see the new songs.
+    pub fn replace_songs<'a>(
+        &self,
+        songs: impl IntoIterator<Item = &'a Song>,
+    ) -> tantivy::Result<()> {
+        let mut index_writer = self.index.writer(50_000_000)?;
+        index_writer.delete_all_documents()?;
+
+        for (order, song) in songs.into_iter().enumerate() {
+            let mut doc = Document::new();
+            doc.add_i64(self.rowid_field, song.row_id);
+            doc.add_u64(self.order_field, order as _);
+            doc.add_text(self.title_field, song.title.clone());
+            doc.add_text(self.artist_field, song.artist.clone());
+            doc.add_f64(self.duration_field, song.duration as _);
+            if let Some(song_language) = &song.language {
+                doc.add_text(self.language_field, song_language.to_owned());
+            }
+            if let Some(song_year) = song.year {
+                doc.add_text(self.year_field, song_year.to_string());
+            }
+            if let Some(song_lyrics) = &song.lyrics {
+                doc.add_text(self.lyrics_field, song_lyrics);
+            }
+            doc.add_bool(self.duet_field, song.duet);
+            doc.add_bool(self.explicit_field, song.explicit);
+            if let Some(cover) = &song.cover_path {
+                doc.add_text(self.cover_field, cover);
+            }
+            doc.add_text(self.audio_field, &song.audio_path);
+            if let Some(tempo) = song.tempo {
+                doc.add_f64(self.tempo_field, tempo);
+            }
+            index_writer.add_document(doc)?;
+        }
+
+        index_writer.commit()?;
+        // Don't wait for the reader to notice the commit on its own.
+        self.reader.reload()
+    }
+
+    /// Applies the filters for user facing searches.
+    fn filtered(&self, query: Box<dyn Query>) -> Box<dyn Query> {
+        if self.family_friendly {
+            Box::new(BooleanQuery::new(vec![
+                (Occur::Must, query),
+                (
+                    Occur::MustNot,
+                    Box::new(TermQuery::new(
+                        Term::from_field_bool(self.explicit_field, true),
+                        IndexRecordOption::Basic,
+                    )),
+                ),
+            ]))
+        } else {
+            query
+        }
+    }
+
+    fn to_song(&self, song: &Document) -> Song {
+        Song {
+            row_id: song.get_first(self.rowid_field).unwrap().as_i64().unwrap(),
+            title: song
+                .get_first(self.title_field)
+                .unwrap()
+                .as_text()
+                .unwrap()
+                .to_owned(),
+            artist: song
+                .get_first(self.artist_field)
+                .unwrap()
+                .as_text()
+                .unwrap()
+                .to_owned(),
+            language: song
+                .get_first(self.language_field)
+                .map(|language| language.as_text().unwrap().to_owned()),
+            year: song
+                .get_first(self.year_field)
+                .map(|year| year.as_text().unwrap().parse().unwrap()),
+            duration: song
+                .get_first(self.duration_field)
+                .unwrap()
+                .as_f64()
+                .unwrap(),
+            lyrics: song
+                .get_first(self.lyrics_field)
+                .map(|lyrics| lyrics.as_text().unwrap().to_owned()),
+            duet: song
+                .get_first(self.duet_field)
+                .map(|duet| duet.as_bool().unwrap_or_default())
+                .unwrap_or_default(),
+            explicit: song
+                .get_first(self.explicit_field)
+                .map(|explicit| explicit.as_bool().unwrap_or_default())
+                .unwrap_or_default(),
+            cover_path: song
+                .get_first(self.cover_field)
+                .map(|cover| cover.as_text().unwrap().to_owned()),
+            audio_path: song
+                .get_first(self.audio_field)
+                .map(|cover| cover.as_text().unwrap().to_owned())
+                .unwrap_or_default(),
+            tempo: song
+                .get_first(self.tempo_field)
+                .and_then(|tempo| tempo.as_f64()),
+        }
+    }
+
+    /// How many songs are in the index.
+    pub fn doc_count(&self) -> usize {
+        self.reader.searcher().num_docs() as usize
     }
 
     fn search_internal<OrderValue, C: Collector<Fruit = Vec<(OrderValue, DocAddress)>>>(
@@ -144,54 +268,29 @@ impl SearchIndex {
 
         results
             .into_iter()
-            .map(|(_, address)| {
-                let song = searcher.doc(address)?;
-
-                let song = Song {
-                    row_id: song.get_first(self.rowid_field).unwrap().as_i64().unwrap(),
-                    title: song
-                        .get_first(self.title_field)
-                        .unwrap()
-                        .as_text()
-                        .unwrap()
-                        .to_owned(),
-                    artist: song
-                        .get_first(self.artist_field)
-                        .unwrap()
-                        .as_text()
-                        .unwrap()
-                        .to_owned(),
-                    language: song
-                        .get_first(self.language_field)
-                        .map(|language| language.as_text().unwrap().to_owned()),
-                    year: song
-                        .get_first(self.year_field)
-                        .map(|year| year.as_text().unwrap().parse().unwrap()),
-                    duration: song
-                        .get_first(self.duration_field)
-                        .unwrap()
-                        .as_f64()
-                        .unwrap(),
-                    lyrics: song
-                        .get_first(self.lyrics_field)
-                        .map(|lyrics| lyrics.as_text().unwrap().to_owned()),
-                    duet: song
-                        .get_first(self.duet_field)
-                        .map(|duet| duet.as_bool().unwrap_or_default())
-                        .unwrap_or_default(),
-                    cover_path: song
-                        .get_first(self.cover_field)
-                        .map(|cover| cover.as_text().unwrap().to_owned()),
-                    audio_path: song
-                        .get_first(self.audio_field)
-                        .map(|cover| cover.as_text().unwrap().to_owned())
-                        .unwrap_or_default(),
-                };
-                Ok(song)
-            })
+            .map(|(_, address)| Ok(self.to_song(&searcher.doc(address)?)))
             .collect()
     }
 
+    /// Searches by relevance. Songs with equal scores are sorted by artist and title, because
+    /// tantivy returns those in arbitrary order.
+    fn search_by_score(&self, query: &dyn Query, limit: usize) -> tantivy::Result<Vec<Song>> {
+        let searcher = self.reader.searcher();
+        let results = searcher.search(query, &TopDocs::with_limit(limit))?;
+
+        let mut songs = results
+            .into_iter()
+            .map(|(score, address)| Ok((score, self.to_song(&searcher.doc(address)?))))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        songs.sort_by(|(score1, song1), (score2, song2)| {
+            score2
+                .total_cmp(score1)
+                .then_with(|| song1.artist.cmp(&song2.artist))
+                .then_with(|| song1.title.cmp(&song2.title))
+        });
+        Ok(songs.into_iter().map(|(_, song)| song).collect())
+    }
+
     fn search_and_convert<OrderValue, C: Collector<Fruit = Vec<(OrderValue, DocAddress)>>>(
         &self,
         query: &dyn Query,
@@ -205,29 +304,118 @@ impl SearchIndex {
             .collect())
     }
 
-    pub fn search(&self, query: &str) -> tantivy::Result<Vec<serde_json::Value>> {
-        self.search_and_convert(
-            &self.query_parser.parse_query(query)?,
-            TopDocs::with_limit(50),
-        )
+    /// Parses a user query, only looking at the given field.
+    fn parse_query(&self, query: &str, field: SearchField) -> tantivy::Result<Box<dyn Query>> {
+        let field = match field {
+            SearchField::Any => return Ok(self.query_parser.parse_query(query)?),
+            SearchField::Artist => self.artist_field,
+            SearchField::Title => self.title_field,
+        };
+        let mut query_parser = QueryParser::for_index(&self.index, vec![field]);
+        query_parser.set_conjunction_by_default();
+        Ok(query_parser.parse_query(query)?)
+    }
+
+    pub fn search(
+        &self,
+        query: &str,
+        field: Option<SearchField>,
+    ) -> tantivy::Result<Vec<serde_json::Value>> {
+        let songs = self.search_by_score(
+            &self.filtered(self.parse_query(query, field.unwrap_or_default())?),
+            50,
+        )?;
+
+        Ok(songs
+            .into_iter()
+            .map(|song| serde_json::to_value(song).unwrap())
+            .collect())
+    }
+
+    /// Runs [`Self::search`] on the blocking thread pool and gives up waiting after `timeout`.
+    /// Note that tantivy can't be interrupted, so the search itself still runs to completion in the background.
+    pub async fn search_with_timeout(
+        &self,
+        query: String,
+        field: Option<SearchField>,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<serde_json::Value>> {
+        let index = self.clone();
+        let search = tokio::task::spawn_blocking(move || index.search(&query, field));
+        match tokio::time::timeout(timeout, search).await {
+            Ok(result) => Ok(result??),
+            Err(_) => Err(anyhow::anyhow!("Search timed out after {timeout:?}")),
+        }
+    }
+
+    /// Like [`Self::search`], but only returns the row ids of the matching songs.
+    pub fn search_ids(&self, query: &str, limit: usize) -> tantivy::Result<Vec<i64>> {
+        let searcher = self.reader.searcher();
+        let results = searcher.search(
+            &self.filtered(self.query_parser.parse_query(query)?),
+            &TopDocs::with_limit(limit),
+        )?;
+
+        results
+            .into_iter()
+            .map(|(_, address)| {
+                let song = searcher.doc(address)?;
+                Ok(song.get_first(self.rowid_field).unwrap().as_i64().unwrap())
+            })
+            .collect()
+    }
+
+    /// Searches for songs that are between `min` and `max` seconds long (inclusive).
+    /// An empty query matches every song in the range.
+    pub fn search_song_duration_range(
+        &self,
+        query: &str,
+        min: f64,
+        max: f64,
+        limit: usize,
+    ) -> tantivy::Result<Vec<Song>> {
+        let range: Box<dyn Query> = Box::new(RangeQuery::new_f64_bounds(
+            "duration".to_owned(),
+            Bound::Included(min),
+            Bound::Included(max),
+        ));
+        let query = if query.trim().is_empty() {
+            range
+        } else {
+            Box::new(BooleanQuery::new(vec![
+                (Occur::Must, self.query_parser.parse_query(query)?),
+                (Occur::Must, range),
+            ]))
+        };
+        self.search_by_score(&self.filtered(query), limit)
     }
 
     pub fn search_song(&self, query: &str, limit: usize) -> tantivy::Result<Vec<Song>> {
-        self.search_internal(
-            &self.query_parser.parse_query(query)?,
-            TopDocs::with_limit(limit),
-        )
+        self.search_by_score(&self.query_parser.parse_query(query)?, limit)
+    }
+
+    /// Runs [`Self::search_song`] on the blocking thread pool. Searching is CPU bound and would
+    /// otherwise occupy an async worker thread (and with it everything else scheduled there,
+    /// like sending updates to listeners) for as long as it takes.
+    pub async fn search_song_blocking(
+        &self,
+        query: String,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Song>> {
+        let index = self.clone();
+        Ok(tokio::task::spawn_blocking(move || index.search_song(&query, limit)).await??)
     }
 
     pub fn paginated(&self, pagination: Pagination) -> tantivy::Result<Vec<serde_json::Value>> {
         let collector = TopDocs::with_limit(pagination.per_page.min(100) as _)
             .and_offset(pagination.offset as _)
             .order_by_fast_field("order", tantivy::Order::Asc);
-        if let Some(query) = &pagination.query {
-            self.search_and_convert::<u64, _>(&self.query_parser.parse_query(query)?, collector)
+        let query = if let Some(query) = &pagination.query {
+            self.query_parser.parse_query(query)?
         } else {
-            self.search_and_convert::<u64, _>(&AllQuery, collector)
-        }
+            Box::new(AllQuery)
+        };
+        self.search_and_convert::<u64, _>(&self.filtered(query), collector)
     }
 
     pub fn random_picks(
@@ -238,14 +426,28 @@ impl SearchIndex {
         let collector = TopDocs::with_limit(count)
             .custom_score(|_: &SegmentReader| |_: DocId| rand::thread_rng().gen::<u32>());
 
-        if let Some(query) = query {
-            self.search_and_convert(&self.query_parser.parse_query(query)?, collector)
+        let query = if let Some(query) = query {
+            self.query_parser.parse_query(query)?
         } else {
-            self.search_and_convert(&AllQuery, collector)
-        }
+            Box::new(AllQuery)
+        };
+        self.search_and_convert(&self.filtered(query), collector)
     }
 }
 
+/// Splits the text into lowercase words with accents removed, the same way the default tokenizer does.
+pub fn normalized_words(text: &str) -> Vec<String> {
+    let mut analyzer = TextAnalyzer::builder(SimpleTokenizer::default())
+        .filter(LowerCaser)
+        .filter(AsciiFoldingFilter)
+        .build();
+    let mut words = Vec::new();
+    analyzer
+        .token_stream(text)
+        .process(&mut |token| words.push(token.text.clone()));
+    words
+}
+
 pub fn urlencode_path(path_bytes: impl IntoIterator<Item = u8>) -> String {
     let mut encoded = String::new();
 
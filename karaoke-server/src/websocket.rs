@@ -3,16 +3,20 @@ use std::{net::SocketAddr, sync::Arc};
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        ConnectInfo, State, WebSocketUpgrade,
+        ConnectInfo, Query, State, WebSocketUpgrade,
     },
     response::IntoResponse,
 };
 use futures_util::{select, FutureExt, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
 use tokio::sync::mpsc::unbounded_channel;
 use uuid::Uuid;
 
-use crate::AppState;
+use crate::{
+    now_playing::{AddOptions, AddResult, BatchResult, BulkAdd, Direction, EnergyCurve, HistoricalPlay, PlayResult, QueueOp},
+    reload_catalog, AppState,
+};
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -20,30 +24,112 @@ use crate::AppState;
 enum Command {
     Authenticate { password: String },
     // If not password is set, tha song CAN NOT be deleted
-    Add { song: i64, singer: String, password: Option<String> },
+    // Only admins can add with a priority above 0
+    // Retrying an add with the same key doesn't add the song again
+    // The singer is remembered for the device, see /api/last_singer
+    // The performer only needs to be set if someone else than the singer sings the song
+    // The user is a stable id of the singer (e.g. their login), it tells singers with the same name apart
+    // Replies with the outcome, e.g. why the song was refused
+    Add { song: i64, singer: String, password: Option<String>, token: Option<String>, priority: Option<u8>, key: Option<String>, device: Option<String>, performer: Option<String>, user: Option<String> },
     Play { id: Uuid },
+    PlayFront,
+    AddMany { songs: Vec<BulkAdd> },
+    // Replies with the entry id and the code the singer can claim the slot with
+    ReserveSlot,
+    ClaimSlot { code: String, song: i64, singer: String, password: Option<String> },
+    Unplay { id: Uuid },
     RemoveAsAdmin { id: Uuid },
     RemoveAsUser { id: Uuid, password: String },
+    // Afterwards, only admins can remove the entries
+    ClearPasswords,
     Swap { id1: Uuid, id2: Uuid },
+    // Positions in the list, including played entries that are kept
+    SwapPositions { i: usize, j: usize },
     MoveAfter { id: Uuid, after: Uuid },
     MoveTop { id: Uuid },
+    // Applied together with a single update, or not at all if one of them fails. Replies with the outcome of each
+    Batch { ops: Vec<QueueOp> },
+    Nudge { id: Uuid, direction: Direction },
+    SpreadArtists,
+    // The same seed always gives the same order
+    Shuffle { seed: Option<u64> },
+    // Only songs with a known tempo are moved
+    ReorderByEnergy { curve: EnergyCurve },
+    PurgePlayed,
+    PruneStale { minutes: i64 },
+    Pin { id: Uuid },
+    // Without a performer, the one who requested the song sings it
+    SetPerformer { id: Uuid, performer: Option<String> },
+    // Shown on the screen instead of the song and singer, without a label they are shown again
+    SetLabel { id: Uuid, label: Option<String> },
+    // Finishes the performance that's playing, with the rating for the song log
+    Rate { id: Uuid, rating: Option<f64> },
+    // Last call: while not accepting, adds are refused but the queue is played out as usual
+    SetAcceptingAdds { accepting: bool },
+    // Tags the song and bug logs with the session, e.g. per event, none ends the session
+    StartSession { session: Option<String> },
+    Unpin { id: Uuid },
+    // Holds the position of the entry while its payment is confirmed, released after the configured timeout
+    HoldPosition { id: Uuid },
+    // Once the payment is confirmed, optionally with a priority so later adds can't get ahead
+    ReleaseHold { id: Uuid, priority: Option<u8> },
+    // Without a time, the entry is unscheduled
+    Schedule {
+        id: Uuid,
+        #[serde(default, with = "time::serde::rfc3339::option")]
+        at: Option<OffsetDateTime>,
+    },
     ReportBug { song: i64, report: String },
+    ArchiveSession,
+    // Plays from another system, to start with a realistic average intermission
+    SeedIntermissions { plays: Vec<HistoricalPlay> },
+    ReloadCatalog,
+    // Replies with the ids of the queued entries whose song isn't in the catalog anymore
+    FindOrphans { remove: bool },
+    PromoteLongestWaiting,
+    Encore,
+    // With the user, entries that have a user are matched by it instead of the name
+    RemoveSinger { singer: String, user: Option<String> },
+    RestoreSinger { singer: String, user: Option<String> },
+    // Replies with the song ids that were requested but aren't in the catalog, with how often
+    MissingSongs,
+}
+
+// Answers to a single client, tagged so they can't be mistaken for the playlist
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase", tag = "reply")]
+enum Reply {
+    Add { result: AddResult },
+    Batch(BatchResult),
+    MissingSongs { songs: Vec<(i64, usize)> },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Resume {
+    /// The playlist version the client has cached from a previous connection.
+    version: Option<u64>,
 }
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(Resume { version }): Query<Resume>,
 ) -> impl IntoResponse {
     log::info!("[{addr:?}] Websocket connected.");
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, state, version))
 }
 
-async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>) {
+async fn handle_socket(
+    socket: WebSocket,
+    who: SocketAddr,
+    state: Arc<AppState>,
+    known_version: Option<u64>,
+) {
     let (mut sender, mut receiver) = socket.split();
 
     let (listen_sender, mut listen_receiver) = unbounded_channel();
-    match state.playlist.subscribe(listen_sender).await {
+    match state.playlist.subscribe(listen_sender, known_version).await {
         Err(err) => log::error!("[{who:?}] {err:?}"),
         Ok(subscription) => {
             let mut authenticated = false;
@@ -77,12 +163,55 @@ async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>)
                                             log::debug!("[{who:?}] Tried to authenticate, result = {authenticated}");
                                             sender.send(Message::Binary(vec![authenticated as u8])).await.map_err(anyhow::Error::from)
                                         }
-                                        Command::Add { song, singer, password} => {
-                                            state.playlist.add(song, singer, password, &state.index).await.map(|_| ())
+                                        Command::Add { song, singer, password, token, priority, key, device, performer, user } if authenticated || priority.unwrap_or_default() == 0 => {
+                                            let options = AddOptions { password, token, priority: priority.unwrap_or_default(), idempotency_key: key, device, performed_by: performer, singer_id: user };
+                                            match state.playlist.add(song, singer, options, &state.index).await {
+                                                Ok(result) => {
+                                                    result.log(who, song);
+                                                    match serde_json::to_string(&Reply::Add { result }) {
+                                                        Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                        Err(err) => Err(err.into()),
+                                                    }
+                                                }
+                                                Err(err) => Err(err),
+                                            }
+                                        }
+                                        Command::AddMany { songs } if authenticated => {
+                                            match state.playlist.add_many(songs, &state.index).await {
+                                                Ok(report) => match serde_json::to_string(&report) {
+                                                    Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                    Err(err) => Err(err.into()),
+                                                },
+                                                Err(err) => Err(err),
+                                            }
+                                        }
+                                        Command::ReserveSlot if authenticated => {
+                                            match serde_json::to_string(&state.playlist.reserve_slot().await) {
+                                                Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                Err(err) => Err(err.into()),
+                                            }
+                                        }
+                                        Command::ClaimSlot { code, song, singer, password } => {
+                                            match state.playlist.claim_slot(&code, song, singer, password, &state.index).await {
+                                                Ok(result) => match serde_json::to_string(&result) {
+                                                    Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                    Err(err) => Err(err.into()),
+                                                },
+                                                Err(err) => Err(err),
+                                            }
                                         }
                                         Command::Play { id } if authenticated => {
                                             state.playlist.play(id, &state.index).await.map(|_| ())
                                         }
+                                        Command::Unplay { id } if authenticated => {
+                                            state.playlist.unplay(id, &state.index).await.map(|_| ())
+                                        }
+                                        Command::PlayFront if authenticated => {
+                                            state.playlist.play_front(&state.index).await.map(|result| match result {
+                                                PlayResult::QueueEmpty => log::info!("Nothing left to play"),
+                                                PlayResult::Played(entry) => log::info!("Playing song {} for {}", entry.song(), entry.performer()),
+                                            })
+                                        }
                                         Command::RemoveAsAdmin { id } if authenticated => {
                                             state.playlist.remove(id, &state.index).await.map(|_| ())
                                         }
@@ -90,18 +219,124 @@ async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>)
                                             state.playlist.remove_if_password_correct(id, password, &state.index).await.map(|_| ())
                                         }
 
+                                        Command::ClearPasswords if authenticated => {
+                                            state.playlist.clear_passwords(&state.index).await.map(|cleared| {
+                                                log::warn!("[{who:?}] Cleared the passwords of {cleared} entries");
+                                            })
+                                        }
                                         Command::Swap { id1, id2 } if authenticated => {
                                             state.playlist.swap(id1, id2, &state.index).await.map(|_| ())
                                         }
+                                        Command::SwapPositions { i, j } if authenticated => {
+                                            state.playlist.swap_positions(i, j, &state.index).await.map(|_| ())
+                                        }
                                         Command::MoveAfter { id, after } if authenticated => {
                                             state.playlist.move_after(id, after, &state.index).await.map(|_| ())
                                         }
                                         Command::MoveTop { id } if authenticated => {
                                             state.playlist.move_top(id, &state.index).await.map(|_| ())
                                         }
+                                        Command::Batch { ops } if authenticated => {
+                                            match state.playlist.batch(ops, &state.index).await {
+                                                Ok(result) => match serde_json::to_string(&Reply::Batch(result)) {
+                                                    Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                    Err(err) => Err(err.into()),
+                                                },
+                                                Err(err) => Err(err),
+                                            }
+                                        }
+                                        Command::Nudge { id, direction } if authenticated => {
+                                            state.playlist.nudge(id, direction, &state.index).await.map(|_| ())
+                                        }
+                                        Command::SetPerformer { id, performer } if authenticated => {
+                                            state.playlist.set_performer(id, performer, &state.index).await.map(|_| ())
+                                        }
+                                        Command::Rate { id, rating } if authenticated => {
+                                            state.playlist.rate(id, rating, &state.index).await.map(|_| ())
+                                        }
+                                        Command::SetLabel { id, label } if authenticated => {
+                                            state.playlist.set_label(id, label, &state.index).await.map(|_| ())
+                                        }
+                                        Command::SetAcceptingAdds { accepting } if authenticated => {
+                                            state.playlist.set_accepting_adds(accepting, &state.index).await
+                                        }
+                                        Command::StartSession { session } if authenticated => {
+                                            state.playlist.start_session(session, &state.index).await
+                                        }
+                                        Command::Pin { id } if authenticated => {
+                                            state.playlist.set_pinned(id, true, &state.index).await.map(|_| ())
+                                        }
+                                        Command::Schedule { id, at } if authenticated => {
+                                            state.playlist.schedule(id, at, &state.index).await.map(|_| ())
+                                        }
+                                        Command::HoldPosition { id } if authenticated => {
+                                            state.playlist.hold_position(id, &state.index).await.map(|_| ())
+                                        }
+                                        Command::ReleaseHold { id, priority } if authenticated => {
+                                            state.playlist.release_hold(id, priority, &state.index).await.map(|_| ())
+                                        }
+                                        Command::Unpin { id } if authenticated => {
+                                            state.playlist.set_pinned(id, false, &state.index).await.map(|_| ())
+                                        }
+                                        Command::PurgePlayed if authenticated => {
+                                            state.playlist.purge_played(&state.index).await.map(|_| ())
+                                        }
+                                        Command::PruneStale { minutes } if authenticated => {
+                                            state.playlist.prune_stale(Duration::minutes(minutes), &state.index).await.map(|_| ())
+                                        }
+                                        Command::SpreadArtists if authenticated => {
+                                            state.playlist.spread_artists(&state.index).await.map(|_| ())
+                                        }
+                                        Command::Shuffle { seed } if authenticated => {
+                                            state.playlist.shuffle(seed, &state.index).await.map(|_| ())
+                                        }
+                                        Command::ReorderByEnergy { curve } if authenticated => {
+                                            state.playlist.reorder_by_energy(curve, &state.index).await.map(|_| ())
+                                        }
                                         Command::ReportBug { song, report } if authenticated => {
                                             state.playlist.report_bug(song, &report, &state.index).await.map(|_| ())
                                         }
+                                        Command::RemoveSinger { singer, user } if authenticated => {
+                                            state.playlist.remove_singer(&singer, user.as_deref(), &state.index).await.map(|_| ())
+                                        }
+                                        Command::RestoreSinger { singer, user } if authenticated => {
+                                            state.playlist.restore_singer(&singer, user.as_deref(), &state.index).await.map(|_| ())
+                                        }
+                                        Command::SeedIntermissions { plays } if authenticated => {
+                                            state.playlist.seed_intermissions(plays, &state.index).await.map(|count| log::info!("[{who:?}] Seeded {count} intermissions"))
+                                        }
+                                        Command::Encore if authenticated => {
+                                            state.playlist.encore(&state.index).await.map(|_| ())
+                                        }
+                                        Command::PromoteLongestWaiting if authenticated => {
+                                            state.playlist.promote_longest_waiting(&state.index).await.map(|_| ())
+                                        }
+                                        Command::FindOrphans { remove } if authenticated => {
+                                            match state.playlist.find_orphans(remove, &state.index).await {
+                                                Ok(orphans) => match serde_json::to_string(&orphans) {
+                                                    Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                    Err(err) => Err(err.into()),
+                                                },
+                                                Err(err) => Err(err),
+                                            }
+                                        }
+                                        Command::MissingSongs if authenticated => {
+                                            let songs = state.playlist.missing_song_requests().await;
+                                            match serde_json::to_string(&Reply::MissingSongs { songs }) {
+                                                Ok(json) => sender.send(Message::Text(json)).await.map_err(anyhow::Error::from),
+                                                Err(err) => Err(err.into()),
+                                            }
+                                        }
+                                        Command::ReloadCatalog if authenticated => {
+                                            reload_catalog(&state).await
+                                        }
+                                        Command::ArchiveSession if authenticated => {
+                                            if let Some(archive_dir) = &state.archive_dir {
+                                                state.playlist.archive_session(archive_dir, &state.index).await.map(|path| log::info!("Session archived to {path:?}"))
+                                            } else {
+                                                Err(anyhow::anyhow!("No archive directory configured"))
+                                            }
+                                        }
                                         _ => sender.send(Message::Text("Unauthenticated".to_owned())).await.map_err(anyhow::Error::from),
                                     };
                                     if let Err(err) = result {
@@ -9,6 +9,7 @@ import 'package:karaokeparty/api/api.dart';
 import 'package:karaokeparty/api/cubit/playlist_cubit.dart';
 import 'package:karaokeparty/i18n/strings.g.dart';
 import 'package:karaokeparty/main.dart';
+import 'package:karaokeparty/model/own_entries.dart';
 import 'package:karaokeparty/model/playlist_entry.dart';
 import 'package:shared_preferences/shared_preferences.dart';
 import 'package:uuid/uuid.dart';
@@ -17,9 +18,12 @@ import 'package:web_socket_channel/web_socket_channel.dart';
 part 'connection_state.dart';
 
 class ConnectionCubit extends Cubit<WebSocketConnectionState> {
-  ConnectionCubit(this.sharedPreferences) : super(const InitialWebSocketConnectionState());
+  ConnectionCubit(this.sharedPreferences)
+      : ownEntries = OwnEntries(sharedPreferences),
+        super(const InitialWebSocketConnectionState());
 
   SharedPreferences sharedPreferences;
+  final OwnEntries ownEntries;
   Completer<bool>? _loginListener;
   UuidValue? _password;
 
@@ -76,7 +80,12 @@ class ConnectionCubit extends Cubit<WebSocketConnectionState> {
 
     final languages = (jsonDecode(languagesResponse.body) as List).whereType<String>().toList(growable: false);
     emit(WebSocketConnectedState(
-        sink: channel.sink, songCount: songCount, isAdmin: false, languages: languages, password: _password));
+        sink: channel.sink,
+        songCount: songCount,
+        isAdmin: false,
+        languages: languages,
+        password: _password,
+        ownEntries: ownEntries));
 
     channel.stream.listen((message) {
       if (message is String) {
@@ -88,6 +97,10 @@ class ConnectionCubit extends Cubit<WebSocketConnectionState> {
           return;
         }
         log.d('Received websocket message $json');
+        if (json.containsKey('reply') || json.containsKey('event')) {
+          // Answers to commands and events aren't playlist updates.
+          return;
+        }
         try {
           final playHistoryJson = json['playHistory'];
           final List<PlaylistEntry> playHistory = (playHistoryJson is List<dynamic>)
@@ -98,6 +111,7 @@ class ConnectionCubit extends Cubit<WebSocketConnectionState> {
               .map((entry) => PlaylistEntry.fromJson(entry as Map<String, dynamic>))
               .toList(growable: false);
 
+          ownEntries.update(songQueue);
           playlist.update(playHistory: playHistory, songQueue: songQueue);
         } catch (e) {
           log.e('Failed parsing server message: $e');
@@ -109,7 +123,12 @@ class ConnectionCubit extends Cubit<WebSocketConnectionState> {
           _loginListener = null;
         }
         emit(WebSocketConnectedState(
-            sink: channel.sink, songCount: songCount, isAdmin: success, languages: languages, password: _password));
+            sink: channel.sink,
+            songCount: songCount,
+            isAdmin: success,
+            languages: languages,
+            password: _password,
+            ownEntries: ownEntries));
       }
     }, onError: (error) {
       log.e('Websocket connection failed: $error');
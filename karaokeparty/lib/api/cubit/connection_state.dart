@@ -19,6 +19,7 @@ final class WebSocketConnectedState extends WebSocketConnectionState {
     required this.isAdmin,
     required this.languages,
     required this.password,
+    required this.ownEntries,
   });
 
   final WebSocketSink sink;
@@ -26,8 +27,10 @@ final class WebSocketConnectedState extends WebSocketConnectionState {
   final bool isAdmin;
   final List<String> languages;
   final UuidValue? password;
+  final OwnEntries ownEntries;
 
   void submitSong({required String singer, required int songId}) {
+    ownEntries.submitted(song: songId, singer: singer);
     sink.add(jsonEncode({
       'cmd': 'add',
       'song': songId,
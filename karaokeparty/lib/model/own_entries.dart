@@ -0,0 +1,41 @@
+import 'package:karaokeparty/model/playlist_entry.dart';
+import 'package:shared_preferences/shared_preferences.dart';
+import 'package:uuid/uuid.dart';
+
+/// The playlist entries that were added from this device, so they can be removed again.
+///
+/// The server doesn't say who added an entry, so submitted songs are matched against the entries
+/// that show up in the playlist afterwards.
+final class OwnEntries {
+  OwnEntries(this._sharedPreferences) : _ids = (_sharedPreferences.getStringList(_key) ?? const []).toSet();
+
+  static const _key = 'own_entries';
+
+  final SharedPreferences _sharedPreferences;
+  final Set<String> _ids;
+  final List<({int song, String singer})> _pending = [];
+
+  bool contains(UuidValue id) => _ids.contains(id.uuid);
+
+  void submitted({required int song, required String singer}) {
+    _pending.add((song: song, singer: singer));
+  }
+
+  /// Claims the newest matching entry for each pending submission.
+  void update(List<PlaylistEntry> entries) {
+    var changed = false;
+    _pending.removeWhere((submission) {
+      for (final entry in entries.reversed) {
+        if (entry.song == submission.song && entry.singer == submission.singer && !_ids.contains(entry.id.uuid)) {
+          _ids.add(entry.id.uuid);
+          changed = true;
+          return true;
+        }
+      }
+      return false;
+    });
+    if (changed) {
+      _sharedPreferences.setStringList(_key, _ids.toList(growable: false));
+    }
+  }
+}
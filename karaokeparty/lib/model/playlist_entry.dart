@@ -5,21 +5,14 @@ final class PlaylistEntry {
   final int song;
   final String singer;
   final DateTime? predictedEnd;
-  final String? passwordHash;
 
-  PlaylistEntry(
-      {required this.id,
-      required this.song,
-      required this.singer,
-      required this.predictedEnd,
-      required this.passwordHash});
+  PlaylistEntry({required this.id, required this.song, required this.singer, required this.predictedEnd});
 
   PlaylistEntry.fromJson(Map<String, dynamic> json)
       : id = UuidValue.fromString(json['id']),
         song = json['song'] as int,
-        singer = json['singer'],
-        predictedEnd = json['predictedEnd'] == null ? null : DateTime.tryParse(json['predictedEnd']),
-        passwordHash = json['passwordHash'] as String?;
+        singer = json['performedBy'] ?? json['requestedBy'] ?? json['singer'],
+        predictedEnd = json['predictedEnd'] == null ? null : DateTime.tryParse(json['predictedEnd']);
 
   @override
   String toString() => "[PlaylistEntry $id: song = $song, singer = $singer, predictedEnd = $predictedEnd]";
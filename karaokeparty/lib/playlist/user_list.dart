@@ -3,6 +3,7 @@ import 'package:flutter/widgets.dart';
 import 'package:karaokeparty/api/api.dart';
 import 'package:karaokeparty/api/song_cache.dart';
 import 'package:karaokeparty/main.dart';
+import 'package:karaokeparty/model/own_entries.dart';
 import 'package:karaokeparty/model/playlist_entry.dart';
 import 'package:karaokeparty/now_playing/now_playing.dart';
 import 'package:karaokeparty/widgets/song_card.dart';
@@ -15,7 +16,7 @@ class UserList extends StatelessWidget {
     required this.songCache,
     required this.songQueue,
     required this.songQueueNowPlaying,
-    required this.passwordHash,
+    required this.ownEntries,
     required this.onRemove,
   });
 
@@ -23,7 +24,7 @@ class UserList extends StatelessWidget {
   final ServerApi api;
   final List<PlaylistEntry> songQueue;
   final int? songQueueNowPlaying;
-  final String? passwordHash;
+  final OwnEntries ownEntries;
   final void Function(UuidValue id)? onRemove;
 
   @override
@@ -32,7 +33,7 @@ class UserList extends StatelessWidget {
       primary: true,
       items: songQueue,
       itemBuilder: (context, itemAnimation, item, i) {
-        final canRemove = passwordHash != null && onRemove != null && item.passwordHash == passwordHash;
+        final canRemove = onRemove != null && ownEntries.contains(item.id);
 
         if (i == songQueueNowPlaying) {
           return NowPlaying(songCache: songCache, api: api, entry: item, onRemove: canRemove ? () {} : null);